@@ -0,0 +1,122 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use display_boy::{fit_display_profile, linear_to_srgb8};
+use image::Rgb;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage:
+    display-boy-cli fit-profile [--mode <colour mode>] <raw> <photo> [<raw> <photo> ...]
+
+fit-profile
+    Fits a display profile from raw emulator frames and photos of the same frames on the hardware.
+    Photos must be cropped to the screen. The colour mode (default 0, GBC) supplies the target gamma.";
+
+fn hex(c: Rgb<u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2])
+}
+
+fn fit_profile(args: &[String]) -> Result<(), String> {
+    let mut color_mode = 0;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--mode" => {
+                color_mode = args
+                    .next()
+                    .and_then(|v| v.parse::<u32>().ok())
+                    .ok_or("--mode needs a number".to_string())?;
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() || files.len() % 2 != 0 {
+        return Err("Expected pairs of raw frames and photos".to_string());
+    }
+
+    let load = |path: &String| {
+        image::open(path)
+            .map(|img| img.to_rgba8())
+            .map_err(|e| format!("Failed to load {}: {}", path, e))
+    };
+    let mut pairs = Vec::new();
+    for pair in files.chunks(2) {
+        pairs.push((load(pair[0])?, load(pair[1])?));
+    }
+
+    let fit = fit_display_profile(&pairs, color_mode)?;
+
+    println!("Raw      Photo    Fitted   Pixels  dE2000");
+    for p in &fit.patches {
+        println!(
+            "{}  {}  {}  {:>6}  {:>6.2}",
+            hex(p.raw),
+            hex(linear_to_srgb8(p.measured)),
+            hex(linear_to_srgb8(p.fitted)),
+            p.pixels,
+            p.delta_e
+        );
+    }
+    println!(
+        "\n{} patches, mean dE2000 {:.2}, max {:.2}\n",
+        fit.patches.len(),
+        fit.mean_delta_e,
+        fit.max_delta_e
+    );
+
+    let prof = &fit.profile;
+    println!("DisplayProfile {{");
+    for (name, v) in [
+        ("r", prof.r),
+        ("gr", prof.gr),
+        ("br", prof.br),
+        ("rg", prof.rg),
+        ("g", prof.g),
+        ("bg", prof.bg),
+        ("rb", prof.rb),
+        ("gb", prof.gb),
+        ("b", prof.b),
+        ("lum", prof.lum),
+        ("gamma", prof.gamma),
+        ("gamma_offset", prof.gamma_offset),
+    ] {
+        println!("    {}: {:.4},", name, v);
+    }
+    println!("}}");
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(|s| s.as_str()) {
+        Some("fit-profile") => fit_profile(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
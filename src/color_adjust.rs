@@ -0,0 +1,122 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::shader_support;
+
+use image::Rgb;
+use shader_support::*;
+
+// Contrast pivots around middle grey, in linear light
+const CONTRAST_PIVOT: f32 = 0.18;
+
+pub struct ColorAdjustment {
+    // Multiplier, 1.0 leaves the image as is
+    pub brightness: f32,
+    // Exponent around middle grey, 1.0 leaves the image as is
+    pub contrast: f32,
+    // 0.0 is greyscale, 1.0 leaves the image as is
+    pub saturation: f32,
+    // Rotation in degrees
+    pub hue: f32,
+    // Colour temperature of the light to balance for, in kelvin. 6500 is neutral, lower is cooler
+    pub temperature: f32,
+    // Green to magenta, 0.0 is neutral
+    pub tint: f32,
+    // Blend from raw colours at 0.0 to fully corrected colours at 1.0
+    pub correction_strength: f32,
+}
+
+impl ColorAdjustment {
+    pub fn is_identity(&self) -> bool {
+        self.brightness == 1.0
+            && self.contrast == 1.0
+            && self.saturation == 1.0
+            && self.hue == 0.0
+            && self.temperature == 6500.0
+            && self.tint == 0.0
+    }
+
+    // Hue rotation around the grey axis, keeping luminance
+    fn hue_matrix(&self) -> Mat3 {
+        let (sin, cos) = self.hue.to_radians().sin_cos();
+        [
+            [
+                0.2126 + cos * 0.7874 - sin * 0.2126,
+                0.7152 - cos * 0.7152 - sin * 0.7152,
+                0.0722 - cos * 0.0722 + sin * 0.9278,
+            ],
+            [
+                0.2126 - cos * 0.2126 + sin * 0.143,
+                0.7152 + cos * 0.2848 + sin * 0.140,
+                0.0722 - cos * 0.0722 - sin * 0.283,
+            ],
+            [
+                0.2126 - cos * 0.2126 - sin * 0.7874,
+                0.7152 - cos * 0.7152 + sin * 0.7152,
+                0.0722 + cos * 0.9278 + sin * 0.0722,
+            ],
+        ]
+    }
+
+    // Gain of each channel for the white balance
+    fn white_balance(&self) -> Rgb<f32> {
+        // Dividing by the light's colour cancels it out, as a camera white balance would
+        let light = color_temperature_rgb(self.temperature.max(1000.0));
+        let tint = Rgb::<f32>([
+            1.0 + 0.1 * self.tint,
+            1.0 - 0.2 * self.tint,
+            1.0 + 0.1 * self.tint,
+        ]);
+        let gain = tint.div(light);
+        // Keep white from getting brighter or darker
+        gain.div_f(gain.luminance())
+    }
+}
+
+// Adjusts a linear image, before any display simulation
+pub fn adjust_image(img: &FloatImage, adjustment: &ColorAdjustment) -> FloatImage {
+    let hue = adjustment.hue_matrix();
+    let white_balance = adjustment.white_balance();
+    let contrast = adjustment.contrast.max(0.0);
+    let saturation = adjustment.saturation.max(0.0);
+    let brightness = adjustment.brightness.max(0.0);
+
+    FloatImage::from_fn(img.width(), img.height(), |x, y| {
+        let c = img.get_pixel(x, y).mult(white_balance).mult_f(brightness);
+        let c = c
+            .div_f(CONTRAST_PIVOT)
+            .pow_f(contrast)
+            .mult_f(CONTRAST_PIVOT);
+        let c = mat3_mul_color(&hue, c);
+        let luminance = c.luminance();
+        let c = lerp_color(Rgb([luminance, luminance, luminance]), c, saturation);
+        c.clamp01()
+    })
+}
+
+// Same as adjust_image, for images kept in gamma space
+pub fn adjust_gamma_image(img: &FloatImage, adjustment: &ColorAdjustment) -> FloatImage {
+    let linear = FloatImage::from_fn(img.width(), img.height(), |x, y| {
+        img.get_pixel(x, y).to_linear()
+    });
+    let adjusted = adjust_image(&linear, adjustment);
+    FloatImage::from_fn(img.width(), img.height(), |x, y| {
+        adjusted.get_pixel(x, y).to_gamma()
+    })
+}
@@ -0,0 +1,289 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::shader_support;
+
+use image::Rgb;
+use shader_support::*;
+
+// The output colour space is sRGB, with a D65 white point
+const SRGB_RED: Chromaticity = Chromaticity { x: 0.64, y: 0.33 };
+const SRGB_GREEN: Chromaticity = Chromaticity { x: 0.30, y: 0.60 };
+const SRGB_BLUE: Chromaticity = Chromaticity { x: 0.15, y: 0.06 };
+const D65: Chromaticity = Chromaticity {
+    x: 0.3127,
+    y: 0.3290,
+};
+
+const BRADFORD: Mat3 = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+#[derive(Clone, Copy)]
+pub struct Chromaticity {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Chromaticity {
+    fn validate(&self, name: &str) -> Result<(), String> {
+        if !(self.x >= 0.0 && self.y > 0.0 && self.x + self.y <= 1.0) {
+            return Err(format!(
+                "{} chromaticity ({}, {}) is not a valid xy coordinate",
+                name, self.x, self.y
+            ));
+        }
+        Ok(())
+    }
+
+    // XYZ with a luminance of 1
+    fn to_xyz(self) -> Rgb<f32> {
+        Rgb([self.x / self.y, 1.0, (1.0 - self.x - self.y) / self.y])
+    }
+
+    fn from_xyz(xyz: Rgb<f32>) -> Option<Self> {
+        let sum = xyz[0] + xyz[1] + xyz[2];
+        if sum <= 0.0 {
+            return None;
+        }
+        Some(Chromaticity {
+            x: xyz[0] / sum,
+            y: xyz[1] / sum,
+        })
+    }
+}
+
+// What a colorimeter reports for a panel
+#[derive(Clone, Copy)]
+pub struct PanelMeasurement {
+    pub red: Chromaticity,
+    pub green: Chromaticity,
+    pub blue: Chromaticity,
+    pub white: Chromaticity,
+    // Luminance of full white, relative to the sRGB reference white
+    pub peak_luminance: f32,
+    // Exponent from raw values to light output
+    pub gamma: f32,
+}
+
+// Matrix from RGB to XYZ, scaled so that white has a luminance of 1
+fn rgb_to_xyz(
+    red: Chromaticity,
+    green: Chromaticity,
+    blue: Chromaticity,
+    white: Chromaticity,
+) -> Result<Mat3, String> {
+    let (r, g, b) = (red.to_xyz(), green.to_xyz(), blue.to_xyz());
+    let primaries = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+    let scale = mat3_mul_color(
+        &mat3_inverse(&primaries).ok_or("Primaries must not lie on a line".to_string())?,
+        white.to_xyz(),
+    );
+    Ok(primaries.map(|row| [row[0] * scale[0], row[1] * scale[1], row[2] * scale[2]]))
+}
+
+pub fn srgb_to_xyz() -> Mat3 {
+    rgb_to_xyz(SRGB_RED, SRGB_GREEN, SRGB_BLUE, D65).unwrap()
+}
+
+// Adapts XYZ colours seen under one white point to how they appear under another
+fn bradford_adaptation(from: Chromaticity, to: Chromaticity) -> Mat3 {
+    let from = mat3_mul_color(&BRADFORD, from.to_xyz());
+    let to = mat3_mul_color(&BRADFORD, to.to_xyz());
+    let scale = [
+        [to[0] / from[0], 0.0, 0.0],
+        [0.0, to[1] / from[1], 0.0],
+        [0.0, 0.0, to[2] / from[2]],
+    ];
+    let inverse = mat3_inverse(&BRADFORD).unwrap();
+    mat3_mul(&inverse, &mat3_mul(&scale, &BRADFORD))
+}
+
+// Colour correction matrix and luminance scale for a measured panel.
+// With adaptation, the panel white is shown as D65 white, as the eye would adapt to it.
+// Without it, the panel white keeps its tint.
+pub fn profile_matrix(m: &PanelMeasurement, adapt_white: bool) -> Result<(Mat3, f32), String> {
+    m.red.validate("Red")?;
+    m.green.validate("Green")?;
+    m.blue.validate("Blue")?;
+    m.white.validate("White")?;
+    if m.peak_luminance.is_nan() || m.peak_luminance <= 0.0 {
+        return Err("Peak luminance must be positive".to_string());
+    }
+
+    let panel = rgb_to_xyz(m.red, m.green, m.blue, m.white)?;
+    let panel = if adapt_white {
+        mat3_mul(&bradford_adaptation(m.white, D65), &panel)
+    } else {
+        panel
+    };
+    let matrix = mat3_mul(&mat3_inverse(&srgb_to_xyz()).unwrap(), &panel);
+
+    // The brightest channel of full white is 1, and the rest of the luminance goes to lum
+    let scale = matrix
+        .iter()
+        .map(|row| row[0] + row[1] + row[2])
+        .fold(f32::MIN, f32::max);
+    if scale <= 0.0 {
+        return Err("White point is outside of sRGB".to_string());
+    }
+    // Luminance above 1 would clip before the matrix, so the excess stays in the matrix
+    let lum = m.peak_luminance * scale;
+    let excess = lum.max(1.0);
+    Ok((
+        matrix.map(|row| row.map(|v| v * excess / scale)),
+        lum / excess,
+    ))
+}
+
+// Inverse of profile_matrix without adaptation, recovering what the panel would measure
+pub fn measure_matrix(matrix: &Mat3, lum: f32, gamma: f32) -> Result<PanelMeasurement, String> {
+    let panel = mat3_mul(&srgb_to_xyz(), matrix);
+    let column = |c: usize| Rgb([panel[0][c], panel[1][c], panel[2][c]]);
+    let white = mat3_mul_color(&panel, Rgb([1.0, 1.0, 1.0]));
+    let invalid = || "Profile matrix does not describe a physical panel".to_string();
+    Ok(PanelMeasurement {
+        red: Chromaticity::from_xyz(column(0)).ok_or_else(invalid)?,
+        green: Chromaticity::from_xyz(column(1)).ok_or_else(invalid)?,
+        blue: Chromaticity::from_xyz(column(2)).ok_or_else(invalid)?,
+        white: Chromaticity::from_xyz(white).ok_or_else(invalid)?,
+        peak_luminance: lum * white[1],
+        gamma,
+    })
+}
+
+// CIELAB relative to D65, from linear sRGB
+pub fn linear_srgb_to_lab(c: Rgb<f32>) -> [f32; 3] {
+    const EPSILON: f32 = 216.0 / 24389.0;
+    const KAPPA: f32 = 24389.0 / 27.0;
+    let to_xyz = srgb_to_xyz();
+    let xyz = mat3_mul_color(&to_xyz, c);
+    let white = mat3_mul_color(&to_xyz, Rgb([1.0, 1.0, 1.0]));
+    let f = |t: f32| {
+        if t > EPSILON {
+            t.cbrt()
+        } else {
+            (KAPPA * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (
+        f(xyz[0] / white[0]),
+        f(xyz[1] / white[1]),
+        f(xyz[2] / white[2]),
+    );
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+// CIEDE2000 colour difference, following Sharma, Wu and Dalal
+pub fn delta_e_2000(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+    use std::f32::consts::PI;
+
+    let [l1, a1, b1] = lab1;
+    let [l2, a2, b2] = lab2;
+
+    let c_mean = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) * 0.5;
+    let c_mean7 = c_mean.powi(7);
+    let g = 0.5 * (1.0 - (c_mean7 / (c_mean7 + 25.0f32.powi(7))).sqrt());
+    let (a1, a2) = (a1 * (1.0 + g), a2 * (1.0 + g));
+    let (c1, c2) = ((a1 * a1 + b1 * b1).sqrt(), (a2 * a2 + b2 * b2).sqrt());
+    let hue = |b: f32, a: f32| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).rem_euclid(2.0 * PI)
+        }
+    };
+    let (h1, h2) = (hue(b1, a1), hue(b2, a2));
+
+    let dl = l2 - l1;
+    let dc = c2 - c1;
+    let dh = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= PI {
+        h2 - h1
+    } else if h2 <= h1 {
+        h2 - h1 + 2.0 * PI
+    } else {
+        h2 - h1 - 2.0 * PI
+    };
+    let dh = 2.0 * (c1 * c2).sqrt() * (dh * 0.5).sin();
+
+    let l_mean = (l1 + l2) * 0.5;
+    let c_mean = (c1 + c2) * 0.5;
+    let h_mean = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= PI {
+        (h1 + h2) * 0.5
+    } else if h1 + h2 < 2.0 * PI {
+        (h1 + h2 + 2.0 * PI) * 0.5
+    } else {
+        (h1 + h2 - 2.0 * PI) * 0.5
+    };
+
+    let t = 1.0 - 0.17 * (h_mean - 30f32.to_radians()).cos()
+        + 0.24 * (2.0 * h_mean).cos()
+        + 0.32 * (3.0 * h_mean + 6f32.to_radians()).cos()
+        - 0.20 * (4.0 * h_mean - 63f32.to_radians()).cos();
+    let d_theta = 30f32.to_radians() * (-((h_mean.to_degrees() - 275.0) / 25.0).powi(2)).exp();
+    let c_mean7 = c_mean.powi(7);
+    let rc = 2.0 * (c_mean7 / (c_mean7 + 25.0f32.powi(7))).sqrt();
+    let l50 = (l_mean - 50.0).powi(2);
+    let sl = 1.0 + 0.015 * l50 / (20.0 + l50).sqrt();
+    let sc = 1.0 + 0.045 * c_mean;
+    let sh = 1.0 + 0.015 * c_mean * t;
+    let rt = -(2.0 * d_theta).sin() * rc;
+
+    let (dl, dc, dh) = (dl / sl, dc / sc, dh / sh);
+    (dl * dl + dc * dc + dh * dh + rt * dc * dh).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gbc;
+
+    #[test]
+    fn measured_presets_round_trip() {
+        for mode in 0..=11 {
+            let preset = gbc::display_profile_preset(mode);
+            let m = measure_matrix(&preset.matrix(), preset.lum, 2.2).unwrap();
+            let (matrix, lum) = profile_matrix(&m, false).unwrap();
+            for (row, expected) in matrix.iter().zip(preset.matrix()) {
+                for (v, e) in row.iter().zip(expected) {
+                    assert!((v - e).abs() < 1e-3, "mode {}: {} != {}", mode, v, e);
+                }
+            }
+            assert!((lum - preset.lum).abs() < 1e-3, "mode {}", mode);
+        }
+    }
+
+    #[test]
+    fn bright_panel_keeps_lum_in_range() {
+        let preset = gbc::display_profile_preset(0);
+        let mut m = measure_matrix(&preset.matrix(), preset.lum, 2.2).unwrap();
+        m.peak_luminance *= 2.0;
+        let (matrix, lum) = profile_matrix(&m, false).unwrap();
+        assert!(lum <= 1.0);
+        let white: f32 = matrix[1].iter().sum::<f32>() * lum;
+        let expected: f32 = preset.matrix()[1].iter().sum::<f32>() * preset.lum * 2.0;
+        assert!((white - expected).abs() < 1e-3);
+    }
+}
@@ -38,27 +38,398 @@
    )
 
    Changes from original:
-   - Removes screen curvature
-   - Changes dotmask to be source size dependent
+   - Screen curvature is optional and off by default
+   - Changes dotmask to be source size dependent, and adds other mask types
    - Changes Lanczos to normal Lanczos2
    - And obviously written in Rust
 */
 
-use crate::{scaling, shader_support};
+use crate::{lut, scaling, shader_support};
 
 use image::{GenericImage, GenericImageView, Rgb, Rgba, RgbaImage};
+use lut::{Lut3d, LutInterpolation};
 use scaling::*;
 use shader_support::*;
 
-pub const CRT_MARGIN: u32 = 4;
-pub const CRT_SCANLINE_WEIGHT: f32 = 0.3;
-pub const CRT_LUM: f32 = 0.0;
-pub const CRT_DOT_MASK: f32 = 0.05;
-pub const CRT_GAMMA: f32 = 2.5;
-pub const CRT_LANCZOS_SIZE: i32 = 2;
+// Colour LUT applied to the source, authored in the gamma of the tube
+pub enum CrtLut {
+    // The bundled 32x32x32 strip
+    Builtin,
+    // Skips the LUT, leaving the source colours as they are
+    Identity,
+    Custom(Lut3d),
+}
+
+fn builtin_lut() -> Lut3d {
+    let lut_img = image::load_from_memory(include_bytes!("crt_lut.png"))
+        .unwrap()
+        .to_rgba8();
+    Lut3d::from_strip(&lut_img).unwrap()
+}
+
+// Largest Lanczos radius allowed, as taps on both sides and the centre
+const MAX_LANCZOS_SIZE: i32 = 4;
+const MAX_LANCZOS_TAPS: usize = MAX_LANCZOS_SIZE as usize * 2 + 1;
+
+// Distance of the viewer from the tube, in half screen widths, for tilt perspective
+const VIEW_DISTANCE: f32 = 2.5;
+
+#[derive(Clone, Copy)]
+pub struct CrtGeometry {
+    // Barrel distortion along each axis, 0.0 is flat
+    pub curvature_x: f32,
+    pub curvature_y: f32,
+    // Radius of the rounded corners, relative to the screen height
+    pub corner_radius: f32,
+    // Width of the soft corner edge, relative to the radius
+    pub corner_smoothness: f32,
+    // Above 1.0 crops the edges of the picture, below 1.0 shrinks it
+    pub overscan: f32,
+    // Rotation of the tube in degrees, around the vertical and horizontal axis
+    pub tilt_x: f32,
+    pub tilt_y: f32,
+}
+
+impl Default for CrtGeometry {
+    fn default() -> Self {
+        CrtGeometry {
+            curvature_x: 0.0,
+            curvature_y: 0.0,
+            corner_radius: 0.0,
+            corner_smoothness: 0.0,
+            overscan: 1.0,
+            tilt_x: 0.0,
+            tilt_y: 0.0,
+        }
+    }
+}
+
+impl CrtGeometry {
+    fn is_flat(&self) -> bool {
+        self.curvature_x == 0.0
+            && self.curvature_y == 0.0
+            && self.corner_radius == 0.0
+            && self.overscan == 1.0
+            && self.tilt_x == 0.0
+            && self.tilt_y == 0.0
+    }
+
+    // Undoes the perspective of a plane turned by angle around one axis, a is along that turn
+    fn untilt(a: f32, b: f32, angle: f32) -> (f32, f32) {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let a = a / (cos - a * sin / VIEW_DISTANCE);
+        (a, b * (1.0 + a * sin / VIEW_DISTANCE))
+    }
+
+    // Maps an output position to the tube surface, with the corner coverage.
+    // Both are in 0..1, None is off the tube.
+    fn map(&self, x: f32, y: f32, aspect: f32, texel_size_y: f32) -> Option<(f32, f32, f32)> {
+        let (cx, cy) = (x * 2.0 - 1.0, y * 2.0 - 1.0);
+        let (cx, cy) = Self::untilt(cx, cy, self.tilt_x);
+        let (cy, cx) = Self::untilt(cy, cx, self.tilt_y);
+
+        // Barrel distortion, as in crt-lottes
+        let (cx, cy) = (
+            cx * (1.0 + cy * cy * self.curvature_x),
+            cy * (1.0 + cx * cx * self.curvature_y),
+        );
+        let (cx, cy) = (cx / self.overscan, cy / self.overscan);
+        let (u, v) = (cx * 0.5 + 0.5, cy * 0.5 + 0.5);
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
+        }
+
+        // Distance into each rounded corner, in units of the screen height
+        let radius = self.corner_radius;
+        let corner = if radius > 0.0 {
+            let edge_x = u.min(1.0 - u) * aspect;
+            let edge_y = v.min(1.0 - v);
+            let (dx, dy) = (radius - edge_x.min(radius), radius - edge_y.min(radius));
+            let distance = (dx * dx + dy * dy).sqrt();
+            let softness = (self.corner_smoothness * radius).max(texel_size_y);
+            ((radius - distance) / softness).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        Some((u, v, corner))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MaskType {
+    // Green and magenta dots, the original CRT Geom mask
+    Dot,
+    // Continuous RGB stripes, as on a Trinitron
+    ApertureGrille,
+    // RGB stripes broken into staggered slots
+    Slot,
+    // Triads of round dots in a delta pattern
+    Shadow,
+    // RGB stripes with a black gap after each triad, like LCD sub-pixels
+    RgbStripe,
+}
+
+// Output draws the mask on the output pixels, Source fits one triad into each source pixel
+#[derive(Clone, Copy, PartialEq)]
+pub enum MaskResolution {
+    Output,
+    Source,
+}
+
+impl MaskResolution {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => Self::Source,
+            _ => Self::Output,
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Self::Output => 0,
+            Self::Source => 1,
+        }
+    }
+}
+
+impl MaskType {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => Self::ApertureGrille,
+            2 => Self::Slot,
+            3 => Self::Shadow,
+            4 => Self::RgbStripe,
+            _ => Self::Dot,
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Self::Dot => 0,
+            Self::ApertureGrille => 1,
+            Self::Slot => 2,
+            Self::Shadow => 3,
+            Self::RgbStripe => 4,
+        }
+    }
+
+    // Width of one triad in mask pixels
+    fn period(&self) -> f32 {
+        match self {
+            Self::Dot => 2.0,
+            Self::ApertureGrille | Self::Slot => 3.0,
+            Self::Shadow => 6.0,
+            Self::RgbStripe => 4.0,
+        }
+    }
+
+    // Average light let through, the same for every channel
+    fn mean(&self, strength: f32) -> f32 {
+        match self {
+            Self::Dot => -0.5 * strength + 1.0,
+            Self::ApertureGrille | Self::Shadow => 1.0 - strength * 2.0 / 3.0,
+            Self::Slot => (1.0 - strength * 2.0 / 3.0) * (1.0 - strength * 0.5),
+            Self::RgbStripe => 1.0 - strength * 0.75,
+        }
+    }
+
+    // Channel weights of the mask. x and y are output pixels, or source pixels
+    // less half a pixel, the same as the scanline position.
+    fn weights(&self, resolution: &MaskResolution, x: f32, y: f32, strength: f32) -> Rgb<f32> {
+        let (x, y) = match resolution {
+            MaskResolution::Output => (x, y),
+            // Two mask rows per scanline, so slots and triads still stagger
+            MaskResolution::Source => (x * self.period(), y * 2.0),
+        };
+        let dark = 1.0 - strength;
+        let lit = |channel: usize| {
+            let mut w = Rgb([dark, dark, dark]);
+            if channel < 3 {
+                w[channel] = 1.0;
+            }
+            w
+        };
+        let cell = |pos: f32, period: f32| (phase(pos, period) * period) as usize;
+
+        match self {
+            Self::Dot => {
+                let green_weight = 1.0 - (phase(x, 2.0) * 2.0 - 1.0).abs();
+                lerp_color(
+                    Rgb::<f32>([1.0, dark, 1.0]),
+                    Rgb::<f32>([dark, 1.0, dark]),
+                    green_weight,
+                )
+            }
+            Self::ApertureGrille => lit(cell(x, 3.0).min(2)),
+            Self::Slot => {
+                // Neighbouring columns of slots break on alternate rows
+                let odd = if phase(x, 6.0) < 0.5 { 1.0 } else { 0.0 };
+                let w = lit(cell(x, 3.0).min(2));
+                if phase(y.floor() + odd, 2.0) < 0.5 {
+                    w.mult_f(dark)
+                } else {
+                    w
+                }
+            }
+            Self::Shadow => lit((cell(x + y.floor() * 3.0, 6.0) / 2).min(2)),
+            Self::RgbStripe => lit(cell(x, 4.0).min(3)),
+        }
+    }
+}
+
+// Position inside the period, in 0..1 for negative positions too
+fn phase(pos: f32, period: f32) -> f32 {
+    let p = pos / period;
+    p - p.floor()
+}
+
+#[derive(Clone, Copy)]
+pub struct CrtParams {
+    // Black border around the picture, in source pixels
+    pub margin: u32,
+    // Width of the beam, lower values give darker gaps between scanlines
+    pub scanline_weight: f32,
+    // Extra brightness of the beam
+    pub lum: f32,
+    // Strength of the phosphor mask, 0.0 turns it off
+    pub mask_strength: f32,
+    pub mask: MaskType,
+    pub mask_resolution: MaskResolution,
+    // How much of the brightness taken by the mask is given back, 0.0 to 1.0
+    pub mask_compensation: f32,
+    // Gamma of the tube
+    pub gamma: f32,
+    // Radius of the horizontal Lanczos filter, larger is sharper
+    pub lanczos_size: i32,
+    // Flat unless set
+    pub geometry: CrtGeometry,
+}
+
+impl Default for CrtParams {
+    fn default() -> Self {
+        CrtParams {
+            margin: 4,
+            scanline_weight: 0.3,
+            lum: 0.0,
+            mask_strength: 0.05,
+            mask: MaskType::Dot,
+            mask_resolution: MaskResolution::Source,
+            mask_compensation: 0.0,
+            gamma: 2.5,
+            lanczos_size: 2,
+            geometry: CrtGeometry::default(),
+        }
+    }
+}
+
+impl CrtParams {
+    pub fn preset(preset: u32) -> Self {
+        match preset {
+            // Consumer TV, soft with faint scanlines and a coarse mask
+            1 => CrtParams {
+                margin: 4,
+                scanline_weight: 0.45,
+                lum: 0.05,
+                mask_strength: 0.15,
+                mask: MaskType::Slot,
+                mask_resolution: MaskResolution::Output,
+                mask_compensation: 0.5,
+                gamma: 2.4,
+                lanczos_size: 1,
+                geometry: CrtGeometry::default(),
+            },
+            // PVM
+            2 => CrtParams {
+                margin: 4,
+                scanline_weight: 0.25,
+                lum: 0.0,
+                mask_strength: 0.1,
+                mask: MaskType::ApertureGrille,
+                mask_resolution: MaskResolution::Output,
+                mask_compensation: 0.5,
+                gamma: 2.5,
+                lanczos_size: 2,
+                geometry: CrtGeometry::default(),
+            },
+            // BVM, sharp with deep scanlines and a fine mask
+            3 => CrtParams {
+                margin: 4,
+                scanline_weight: 0.2,
+                lum: 0.05,
+                mask_strength: 0.05,
+                mask: MaskType::ApertureGrille,
+                mask_resolution: MaskResolution::Output,
+                mask_compensation: 0.5,
+                gamma: 2.5,
+                lanczos_size: 3,
+                geometry: CrtGeometry::default(),
+            },
+            // Arcade monitor, bright with a strong mask
+            4 => CrtParams {
+                margin: 2,
+                scanline_weight: 0.3,
+                lum: 0.1,
+                mask_strength: 0.2,
+                mask: MaskType::Shadow,
+                mask_resolution: MaskResolution::Output,
+                mask_compensation: 0.5,
+                gamma: 2.4,
+                lanczos_size: 2,
+                geometry: CrtGeometry::default(),
+            },
+            _ => CrtParams::default(),
+        }
+    }
+
+    // Brightens the image to make up for the light blocked by the mask
+    fn mask_gain(&self) -> f32 {
+        let mean = self.mask.mean(self.mask_strength);
+        1.0 / (1.0 + (mean - 1.0) * self.mask_compensation)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let check = |name: &str, v: f32, low: f32, high: f32| {
+            if !(low..=high).contains(&v) {
+                return Err(format!(
+                    "{} must be between {} and {}, got {}",
+                    name, low, high, v
+                ));
+            }
+            Ok(())
+        };
+        check("Margin", self.margin as f32, 0.0, 32.0)?;
+        check("Scanline weight", self.scanline_weight, 0.05, 1.0)?;
+        check("Brightness", self.lum, -0.5, 1.0)?;
+        check("Mask strength", self.mask_strength, 0.0, 1.0)?;
+        check("Mask compensation", self.mask_compensation, 0.0, 1.0)?;
+        check("Gamma", self.gamma, 1.0, 4.0)?;
+        check(
+            "Lanczos size",
+            self.lanczos_size as f32,
+            1.0,
+            MAX_LANCZOS_SIZE as f32,
+        )?;
+        let g = &self.geometry;
+        check("Horizontal curvature", g.curvature_x, 0.0, 0.5)?;
+        check("Vertical curvature", g.curvature_y, 0.0, 0.5)?;
+        check("Corner radius", g.corner_radius, 0.0, 0.25)?;
+        check("Corner smoothness", g.corner_smoothness, 0.0, 1.0)?;
+        check("Overscan", g.overscan, 0.8, 1.25)?;
+        check("Horizontal tilt", g.tilt_x, -30.0, 30.0)?;
+        check("Vertical tilt", g.tilt_y, -30.0, 30.0)?;
+        Ok(())
+    }
 
-pub const CRT_PWR: f32 =
-    1.0 / ((-0.7 * (1.0 - CRT_SCANLINE_WEIGHT) + 1.0) * (-0.5 * CRT_DOT_MASK + 1.0)) - 1.25;
+    // Blend between the two inverse gamma curves, keeping the overall brightness.
+    // The mask term is CRT-Geom's own, other masks are made up for by mask_gain alone.
+    // Thin scanlines and strong masks would push it past the second curve, blowing out mid tones.
+    fn pwr(&self) -> f32 {
+        let pwr = 1.0
+            / ((-0.7 * (1.0 - self.scanline_weight) + 1.0) * (-0.5 * self.mask_strength + 1.0))
+            - 1.25;
+        pwr.clamp(0.0, 1.0)
+    }
+}
 
 #[inline(always)]
 pub fn scanline_wid(color: Rgb<f32>) -> Rgb<f32> {
@@ -68,73 +439,67 @@ pub fn scanline_wid(color: Rgb<f32>) -> Rgb<f32> {
 }
 
 #[inline(always)]
-pub fn scanline_weights(distance: f32, wid: Rgb<f32>) -> Rgb<f32> {
+pub fn scanline_weights(distance: f32, wid: Rgb<f32>, params: &CrtParams) -> Rgb<f32> {
     wid.mult_f(0.5)
         .inv_sqrt()
-        .mult_f(distance / CRT_SCANLINE_WEIGHT)
+        .mult_f(distance / params.scanline_weight)
         .pow(wid)
         .mult_f(-1.0)
         .exp()
-        .mult_f(CRT_LUM + 1.4)
+        .mult_f(params.lum + 1.4)
         .div(wid.mult_f(0.2).add_f(0.6))
 }
 
 #[inline(always)]
-pub fn crt_inv_gamma(col: Rgb<f32>) -> Rgb<f32> {
+pub fn crt_inv_gamma(col: Rgb<f32>, pwr: f32) -> Rgb<f32> {
     let cir = col.sub_f(1.0);
     let cir = cir.mult(cir);
     lerp_color(
         col.sqrt(),
         (Rgb::<f32>([1.0, 1.0, 1.0]).sub(cir)).sqrt(),
-        CRT_PWR,
+        pwr,
     )
 }
 
-#[inline(always)]
-fn apply_lut_rgb3d(col: Rgb<f32>, lut: &[Rgb<f32>; 32 * 32 * 32]) -> Rgb<f32> {
-    let r = col[0];
-    let g = col[1];
-    let b = col[2];
-
-    let x = (r * 31.0).floor() as usize;
-    let y = (g * 31.0).floor() as usize;
-    let z = (b * 31.0).floor() as usize;
-
-    let dx = r * 31.0 - x as f32;
-    let dy = g * 31.0 - y as f32;
-    let dz = b * 31.0 - z as f32;
-
-    let x_next = (x + 1).min(31);
-    let y_next = (y + 1).min(31);
-    let z_next = (z + 1).min(31);
-
-    let c00 = lerp_color(
-        lut[x + y * 32 + z * 32 * 32],
-        lut[x_next + y * 32 + z * 32 * 32],
-        dx,
-    );
-    let c01 = lerp_color(
-        lut[x + y * 32 + z_next * 32 * 32],
-        lut[x_next + y * 32 + z_next * 32 * 32],
-        dx,
-    );
-    let c10 = lerp_color(
-        lut[x + y_next * 32 + z * 32 * 32],
-        lut[x_next + y_next * 32 + z * 32 * 32],
-        dx,
-    );
-    let c11 = lerp_color(
-        lut[x + y_next * 32 + z_next * 32 * 32],
-        lut[x_next + y_next * 32 + z_next * 32 * 32],
-        dx,
-    );
+// Horizontal Lanczos weights around tex_coord_x, normalized to sum to 1
+fn lanczos_weights_at(
+    tex_coord_x: f32,
+    out_tex_coord_x: f32,
+    src_width_f: f32,
+    src_texel_size_x: f32,
+    lanczos_size: i32,
+) -> [f32; MAX_LANCZOS_TAPS] {
+    let mut weights = [0.0; MAX_LANCZOS_TAPS];
+    let ratio_scale_x = tex_coord_x * src_width_f - 0.5;
+    let xx = ratio_scale_x.floor();
+
+    let mut sum = 0.0;
+    let src_tex_coord_x = xx * src_texel_size_x;
+    for lx in -lanczos_size..=lanczos_size {
+        let src_sample_tex_coord_x = src_tex_coord_x + lx as f32 * src_texel_size_x;
+        let d = clamp(
+            (src_sample_tex_coord_x - out_tex_coord_x) * src_width_f,
+            -lanczos_size as f32,
+            lanczos_size as f32,
+        );
 
-    let c0 = lerp_color(c00, c10, dy);
-    let c1 = lerp_color(c01, c11, dy);
+        let mut w = 1.0;
+        if d != 0.0 {
+            let d = d * std::f32::consts::PI;
+            w = (lanczos_size as f32 * d.sin() * (d / (lanczos_size as f32)).sin()) / (d * d);
+        }
+
+        weights[(lx + lanczos_size) as usize] = w;
+        sum += w;
+    }
 
-    lerp_color(c0, c1, dz)
+    for w in weights.iter_mut().take(lanczos_size as usize * 2 + 1) {
+        *w /= sum;
+    }
+    weights
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn crt(
     img: &FloatImage,
     src_scale: &ScaleInfo,
@@ -142,24 +507,25 @@ pub fn crt(
     explicit_aspect_ratio: bool,
     pixel_aspect_ratio: f32,
     desired_aspect_ratio: f32,
+    correction_strength: f32,
+    params: &CrtParams,
+    lut: &CrtLut,
 ) -> RgbaImage {
-    // Load LUT from embedded PNG
-    let lut_png = include_bytes!("crt_lut.png");
-    let lut_img = image::load_from_memory(lut_png).unwrap().to_rgba8();
-    let mut lut = [Rgb::<f32>([0.0, 0.0, 0.0]); 32 * 32 * 32];
-    for z in 0..32 {
-        for y in 0..32 {
-            for x in 0..32 {
-                let p = lut_img.get_pixel((x + z * 32) as u32, y as u32);
-                lut[x + y * 32 + z * 32 * 32] = Rgb::<f32>([
-                    p[0] as f32 / 255.0,
-                    p[1] as f32 / 255.0,
-                    p[2] as f32 / 255.0,
-                ])
-                .to_linear_from_gamma(CRT_GAMMA);
-            }
+    // Interpolating in linear light, as the tube mixes light rather than signal
+    let builtin;
+    let lut = match lut {
+        CrtLut::Builtin => {
+            builtin = builtin_lut();
+            Some(&builtin)
         }
+        CrtLut::Identity => None,
+        CrtLut::Custom(lut) => Some(lut),
     }
+    .map(|lut| lut.map(|c| c.to_linear_from_gamma(params.gamma)));
+    let apply_lut = |raw: Rgb<f32>| match &lut {
+        Some(lut) => lut.apply(raw, LutInterpolation::Trilinear),
+        None => raw.to_linear_from_gamma(params.gamma),
+    };
 
     let (src_width, src_height) = (img.width(), img.height());
 
@@ -186,14 +552,14 @@ pub fn crt(
         1.0
     };
 
-    let top_margin = CRT_MARGIN + vertical_padding.0;
+    let top_margin = params.margin + vertical_padding.0;
     // The margin is defined to be in a unit after applying aspect ratio stretching
-    let left_margin = (CRT_MARGIN as f32 / output_width_factor).ceil() as u32;
+    let left_margin = (params.margin as f32 / output_width_factor).ceil() as u32;
 
     // Create a source buffer with margins
     let (padded_width, padded_height) = (
         src_width + left_margin * 2,
-        src_height + CRT_MARGIN * 2 + vertical_padding.0 + vertical_padding.1,
+        src_height + params.margin * 2 + vertical_padding.0 + vertical_padding.1,
     );
 
     // Add margin, and apply LUT
@@ -205,7 +571,17 @@ pub fn crt(
         if y < 0 || y >= img.height() as i32 || x < 0 || x >= img.width() as i32 {
             Rgb([0.0, 0.0, 0.0])
         } else {
-            unsafe { apply_lut_rgb3d(img.unsafe_get_pixel(x as u32, y as u32), &lut) }
+            let raw = unsafe { img.unsafe_get_pixel(x as u32, y as u32) };
+            if correction_strength >= 1.0 {
+                apply_lut(raw)
+            } else {
+                // Strength of 0 leaves the phosphor colours uncorrected
+                lerp_color(
+                    raw.to_linear_from_gamma(params.gamma),
+                    apply_lut(raw),
+                    correction_strength.max(0.0),
+                )
+            }
         }
     });
 
@@ -230,63 +606,77 @@ pub fn crt(
 
     let src_texel_size_x = 1.0 / src_width as f32;
 
-    // Precompute Lanczos weights
-    let mut lanczos_weights =
-        vec![[0.0; CRT_LANCZOS_SIZE as usize * 2usize + 1usize]; width as usize];
-    for x in 0..width {
-        let tex_coord_x = out_texel_size_x * (x as f32 + 0.5);
-        let ratio_scale_x = tex_coord_x * src_width_f - 0.5;
-        let xx = ratio_scale_x.floor();
-
-        let mut sum = 0.0;
-        let out_tex_coord_x = x as f32 * out_texel_size_x;
-        let src_tex_coord_x = xx * src_texel_size_x;
-        for lx in -CRT_LANCZOS_SIZE..=CRT_LANCZOS_SIZE {
-            let src_sample_tex_coord_x = src_tex_coord_x + lx as f32 * src_texel_size_x;
-            let d = clamp(
-                (src_sample_tex_coord_x - out_tex_coord_x) * src_width_f,
-                -CRT_LANCZOS_SIZE as f32,
-                CRT_LANCZOS_SIZE as f32,
-            );
-
-            let mut w = 1.0;
-            if d != 0.0 {
-                let d = d * std::f32::consts::PI;
-                w = (CRT_LANCZOS_SIZE as f32 * d.sin() * (d / (CRT_LANCZOS_SIZE as f32)).sin())
-                    / (d * d);
-            }
+    // Precompute Lanczos weights, a curved screen needs them per pixel instead
+    let lanczos_size = params.lanczos_size;
+    let geometry = &params.geometry;
+    let curved = !geometry.is_flat();
+    let lanczos_weights: Vec<[f32; MAX_LANCZOS_TAPS]> = if curved {
+        Vec::new()
+    } else {
+        (0..width)
+            .map(|x| {
+                lanczos_weights_at(
+                    out_texel_size_x * (x as f32 + 0.5),
+                    x as f32 * out_texel_size_x,
+                    src_width_f,
+                    src_texel_size_x,
+                    lanczos_size,
+                )
+            })
+            .collect()
+    };
 
-            lanczos_weights[x as usize][(lx + CRT_LANCZOS_SIZE) as usize] = w;
-            sum += w;
-        }
-
-        for lx in -CRT_LANCZOS_SIZE..=CRT_LANCZOS_SIZE {
-            lanczos_weights[x as usize][(lx + CRT_LANCZOS_SIZE) as usize] /= sum;
-        }
-    }
+    let pwr = params.pwr();
+    let mask_gain = params.mask_gain();
+    let aspect = width as f32 / height as f32;
 
     let mut out = RgbaImage::new(width, height);
 
     for y in 0..height {
-        let tex_coord_y = out_texel_size_y * (y as f32 + 0.5);
-
-        let ratio_scale_y = tex_coord_y * src_height_f - 0.5;
-        let yy = ratio_scale_y.floor();
-        let uv_ratio_y = ratio_scale_y - yy;
-
         for x in 0..width {
             let tex_coord_x = out_texel_size_x * (x as f32 + 0.5);
+            let tex_coord_y = out_texel_size_y * (y as f32 + 0.5);
+
+            // Find where on the tube this pixel lands, so scanlines and the mask follow the surface
+            let (tex_coord_x, tex_coord_y, corner) = if curved {
+                match geometry.map(tex_coord_x, tex_coord_y, aspect, out_texel_size_y) {
+                    Some(mapped) => mapped,
+                    None => {
+                        unsafe { out.unsafe_put_pixel(x, y, Rgba([0, 0, 0, 255])) };
+                        continue;
+                    }
+                }
+            } else {
+                (tex_coord_x, tex_coord_y, 1.0)
+            };
+
+            let ratio_scale_y = tex_coord_y * src_height_f - 0.5;
+            let yy = ratio_scale_y.floor();
+            let uv_ratio_y = ratio_scale_y - yy;
 
             let ratio_scale_x = tex_coord_x * src_width_f - 0.5;
             let xx = ratio_scale_x.floor();
-            let uv_ratio_x = ratio_scale_x - xx;
+
+            let weights_here;
+            let lanczos = if curved {
+                weights_here = lanczos_weights_at(
+                    tex_coord_x,
+                    tex_coord_x - 0.5 * out_texel_size_x,
+                    src_width_f,
+                    src_texel_size_x,
+                    lanczos_size,
+                );
+                &weights_here
+            } else {
+                &lanczos_weights[x as usize]
+            };
 
             let mut col = Rgb::<f32>([0.0, 0.0, 0.0]);
             let mut col2 = Rgb::<f32>([0.0, 0.0, 0.0]);
 
-            // Horizontal only Lanczos using precomputed weights
-            for lx in -CRT_LANCZOS_SIZE..=CRT_LANCZOS_SIZE {
-                let w = lanczos_weights[x as usize][(lx + CRT_LANCZOS_SIZE) as usize];
+            // Horizontal only Lanczos
+            for lx in -lanczos_size..=lanczos_size {
+                let w = lanczos[(lx + lanczos_size) as usize];
 
                 let val = load_buff(xx as i32 + lx, yy as i32);
                 col = col.add(val.mult_f(w));
@@ -301,20 +691,30 @@ pub fn crt(
             // Scanline
             let wid = scanline_wid(col);
             let wid2 = scanline_wid(col2);
-            let weights = scanline_weights(uv_ratio_y, wid);
-            let weights2 = scanline_weights(1.0 - uv_ratio_y, wid2);
+            let weights = scanline_weights(uv_ratio_y, wid, params);
+            let weights2 = scanline_weights(1.0 - uv_ratio_y, wid2, params);
             let color = col.mult(weights).add(col2.mult(weights2));
 
-            // Dotmask
-            let mask_green_weight = 1.0 - (uv_ratio_x * 2.0 - 1.0).abs();
-            let dot_mask_weights = lerp_color(
-                Rgb::<f32>([1.0, 1.0 - CRT_DOT_MASK, 1.0]),
-                Rgb::<f32>([1.0 - CRT_DOT_MASK, 1.0, 1.0 - CRT_DOT_MASK]),
-                mask_green_weight,
+            // Phosphor mask
+            let (mask_x, mask_y) = match params.mask_resolution {
+                MaskResolution::Source => (ratio_scale_x, ratio_scale_y),
+                MaskResolution::Output => (
+                    (tex_coord_x * width as f32).floor(),
+                    (tex_coord_y * height as f32).floor(),
+                ),
+            };
+            let mask_weights = params.mask.weights(
+                &params.mask_resolution,
+                mask_x,
+                mask_y,
+                params.mask_strength,
             );
-            let color = color.mult(dot_mask_weights).clamp01();
+            let color = color
+                .mult(mask_weights)
+                .mult_f(corner * mask_gain)
+                .clamp01();
 
-            let color = crt_inv_gamma(color);
+            let color = crt_inv_gamma(color, pwr);
 
             let p = color.clamp01();
 
@@ -17,11 +17,27 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::shader_support;
+use crate::{scaling, shader_support};
 
-use image::{GenericImage, GenericImageView, Luma, Rgb, Rgba, RgbaImage};
+use image::{GenericImage, GenericImageView, ImageBuffer, Luma, Rgb, Rgba, RgbaImage};
+use scaling::*;
 use shader_support::*;
 
+pub const GB_CAMERA_WIDTH: u32 = 128;
+pub const GB_CAMERA_HEIGHT: u32 = 112;
+pub const GB_FRAME_WIDTH: u32 = 160;
+pub const GB_FRAME_HEIGHT: u32 = 144;
+
+// 0 is the lightest shade, 3 is the darkest
+pub type ShadeImage = ImageBuffer<Luma<u8>, Vec<u8>>;
+
+// Lightest shade still has a faint pixel visible on the LCD
+pub const DEFAULT_SHADE_OPACITY: [f32; 4] = [0.07, 1.0 / 3.0, 2.0 / 3.0, 1.0];
+
+// Output pixels per GB pixel, and the native pixels of bezel margin around the LCD
+pub const LCD_SCALE: u32 = 5;
+pub const LCD_MARGIN: u32 = 5;
+
 #[inline(always)]
 fn load_alpha_checked(buff: &AlphaImage, x: i32, y: i32, width: u32, height: u32) -> f32 {
     if x >= width as i32 || y >= height as i32 || x < 0 || y < 0 {
@@ -37,34 +53,23 @@ fn apply_threshold_kernel(
     y: u32,
     kernel: &[[[f32; 3]; 4]; 4],
     adjustment: &GbColorAdjustment,
-) -> f32 {
+) -> u8 {
     let x = x % 4;
     let y = y % 4;
     let thresholds = kernel[x as usize][y as usize];
-    if l <= thresholds[0] {
-        if adjustment.invert {
-            0.07
-        } else {
-            3.0 / 3.0
-        }
+    let shade = if l <= thresholds[0] {
+        3
     } else if l <= thresholds[1] {
-        if adjustment.invert {
-            1.0 / 3.0
-        } else {
-            2.0 / 3.0
-        }
+        2
     } else if l <= thresholds[2] {
-        if adjustment.invert {
-            2.0 / 3.0
-        } else {
-            1.0 / 3.0
-        }
+        1
     } else {
-        if adjustment.invert {
-            3.0 / 3.0
-        } else {
-            0.07
-        }
+        0
+    };
+    if adjustment.invert {
+        3 - shade
+    } else {
+        shade
     }
 }
 
@@ -194,6 +199,8 @@ pub struct GbDisplayProfile {
     pub background_r: f32,
     pub background_g: f32,
     pub background_b: f32,
+    // Opacity of each shade, before foreground_a is applied
+    pub shade_opacity: [f32; 4],
 }
 
 pub struct GbColorAdjustment {
@@ -204,27 +211,352 @@ pub struct GbColorAdjustment {
     pub edge_enhancement_level: f32,
 }
 
-pub fn gb_mono(
-    img: &FloatImage,
-    profile: &GbDisplayProfile,
+pub enum GbCameraLayout {
+    Normal,
+    Mirror,
+    Montage2,
+    Montage4,
+    PanoramaHorizontal,
+    PanoramaVertical,
+}
+
+impl GbCameraLayout {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => Self::Mirror,
+            2 => Self::Montage2,
+            3 => Self::Montage4,
+            4 => Self::PanoramaHorizontal,
+            5 => Self::PanoramaVertical,
+            _ => Self::Normal,
+        }
+    }
+
+    // Pane rectangles as (x, y, width, height) in the 128x112 camera frame
+    fn panes(&self) -> Vec<(u32, u32, u32, u32)> {
+        let (w, h) = (GB_CAMERA_WIDTH, GB_CAMERA_HEIGHT);
+        match self {
+            Self::Normal | Self::Mirror => vec![(0, 0, w, h)],
+            Self::Montage2 => vec![(0, 0, w, h / 2), (0, h / 2, w, h / 2)],
+            Self::Montage4 => vec![
+                (0, 0, w / 2, h / 2),
+                (w / 2, 0, w / 2, h / 2),
+                (0, h / 2, w / 2, h / 2),
+                (w / 2, h / 2, w / 2, h / 2),
+            ],
+            Self::PanoramaHorizontal => (0..4).map(|i| (i * w / 4, 0, w / 4, h)).collect(),
+            Self::PanoramaVertical => (0..4).map(|i| (0, i * h / 4, w, h / 4)).collect(),
+        }
+    }
+}
+
+pub enum GbCameraFit {
+    // Crop to the camera aspect ratio, filling the whole view
+    Fill,
+    // Show the whole crop region, padded with the lightest shade, even when inverted
+    Fit,
+    Stretch,
+}
+
+impl GbCameraFit {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => Self::Fit,
+            2 => Self::Stretch,
+            _ => Self::Fill,
+        }
+    }
+}
+
+// Crop region of the source photo, normalized to 0-1
+pub struct GbCameraCrop {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+pub enum GbCameraFrame {
+    None,
+    Plain,
+    Film,
+    Custom(ShadeImage),
+}
+
+// Sensor view of a photo, along with the pixels that are padding rather than photo
+pub struct GbCameraView {
+    pub img: FloatImage,
+    pub padding: Vec<bool>,
+}
+
+impl GbCameraView {
+    fn is_padding(&self, x: u32, y: u32) -> bool {
+        self.padding[(y * GB_CAMERA_WIDTH + x) as usize]
+    }
+
+    fn copy_pixel(&mut self, src: &GbCameraView, src_x: u32, x: u32, y: u32) {
+        self.img.put_pixel(x, y, *src.img.get_pixel(src_x, y));
+        self.padding[(y * GB_CAMERA_WIDTH + x) as usize] = src.is_padding(src_x, y);
+    }
+}
+
+pub fn gb_camera_view(photo: &FloatImage, crop: &GbCameraCrop, fit: &GbCameraFit) -> GbCameraView {
+    let (photo_width, photo_height) = (photo.width() as f32, photo.height() as f32);
+    // At least one pixel of the photo is always left in the crop
+    let crop_x = (crop.x.clamp(0.0, 1.0) * photo_width).min(photo_width - 1.0);
+    let crop_y = (crop.y.clamp(0.0, 1.0) * photo_height).min(photo_height - 1.0);
+    let crop_width =
+        (crop.width.clamp(0.0, 1.0) * photo_width).clamp(1.0, (photo_width - crop_x).max(1.0));
+    let crop_height =
+        (crop.height.clamp(0.0, 1.0) * photo_height).clamp(1.0, (photo_height - crop_y).max(1.0));
+    let no_padding = || vec![false; (GB_CAMERA_WIDTH * GB_CAMERA_HEIGHT) as usize];
+
+    let view_aspect_ratio = GB_CAMERA_WIDTH as f32 / GB_CAMERA_HEIGHT as f32;
+    let crop_aspect_ratio = crop_width / crop_height;
+
+    match fit {
+        GbCameraFit::Fill => {
+            let (w, h) = if crop_aspect_ratio > view_aspect_ratio {
+                (crop_height * view_aspect_ratio, crop_height)
+            } else {
+                (crop_width, crop_width / view_aspect_ratio)
+            };
+            GbCameraView {
+                img: resample_area(
+                    photo,
+                    crop_x + (crop_width - w) * 0.5,
+                    crop_y + (crop_height - h) * 0.5,
+                    w,
+                    h,
+                    GB_CAMERA_WIDTH,
+                    GB_CAMERA_HEIGHT,
+                ),
+                padding: no_padding(),
+            }
+        }
+        GbCameraFit::Fit => {
+            let (w, h) = if crop_aspect_ratio > view_aspect_ratio {
+                (
+                    GB_CAMERA_WIDTH,
+                    ((GB_CAMERA_WIDTH as f32 / crop_aspect_ratio).round() as u32).max(1),
+                )
+            } else {
+                (
+                    ((GB_CAMERA_HEIGHT as f32 * crop_aspect_ratio).round() as u32).max(1),
+                    GB_CAMERA_HEIGHT,
+                )
+            };
+            let scaled = resample_area(photo, crop_x, crop_y, crop_width, crop_height, w, h);
+            let (offset_x, offset_y) = ((GB_CAMERA_WIDTH - w) / 2, (GB_CAMERA_HEIGHT - h) / 2);
+            let outside = |x: u32, y: u32| {
+                x < offset_x || y < offset_y || x >= offset_x + w || y >= offset_y + h
+            };
+            GbCameraView {
+                img: FloatImage::from_fn(GB_CAMERA_WIDTH, GB_CAMERA_HEIGHT, |x, y| {
+                    if outside(x, y) {
+                        Rgb([1.0, 1.0, 1.0])
+                    } else {
+                        *scaled.get_pixel(x - offset_x, y - offset_y)
+                    }
+                }),
+                padding: (0..GB_CAMERA_WIDTH * GB_CAMERA_HEIGHT)
+                    .map(|i| outside(i % GB_CAMERA_WIDTH, i / GB_CAMERA_WIDTH))
+                    .collect(),
+            }
+        }
+        GbCameraFit::Stretch => GbCameraView {
+            img: resample_area(
+                photo,
+                crop_x,
+                crop_y,
+                crop_width,
+                crop_height,
+                GB_CAMERA_WIDTH,
+                GB_CAMERA_HEIGHT,
+            ),
+            padding: no_padding(),
+        },
+    }
+}
+
+pub fn gb_camera_compose(
+    photos: &[FloatImage],
+    layout: &GbCameraLayout,
+    crop: &GbCameraCrop,
+    fit: &GbCameraFit,
+) -> GbCameraView {
+    // Every shot first goes through the sensor view: cropped and scaled to 128x112.
+    // Like on the camera, each pane then shows its own region of that view rather than a shrunk copy,
+    // so panning between shots stitches the panorama together.
+    let views: Vec<GbCameraView> = photos
+        .iter()
+        .map(|photo| gb_camera_view(photo, crop, fit))
+        .collect();
+
+    let mut out = GbCameraView {
+        img: FloatImage::new(GB_CAMERA_WIDTH, GB_CAMERA_HEIGHT),
+        padding: vec![false; (GB_CAMERA_WIDTH * GB_CAMERA_HEIGHT) as usize],
+    };
+    if views.is_empty() {
+        return out;
+    }
+
+    // Reuse the photos in order if there are fewer than panes
+    for (i, (pane_x, pane_y, pane_width, pane_height)) in layout.panes().into_iter().enumerate() {
+        let view = &views[i % views.len()];
+        for y in pane_y..pane_y + pane_height {
+            for x in pane_x..pane_x + pane_width {
+                out.copy_pixel(view, x, x, y);
+            }
+        }
+    }
+
+    if let GbCameraLayout::Mirror = layout {
+        // Left half is reflected onto the right half
+        let left = GbCameraView {
+            img: out.img.clone(),
+            padding: out.padding.clone(),
+        };
+        for y in 0..GB_CAMERA_HEIGHT {
+            for x in GB_CAMERA_WIDTH / 2..GB_CAMERA_WIDTH {
+                out.copy_pixel(&left, GB_CAMERA_WIDTH - 1 - x, x, y);
+            }
+        }
+    }
+
+    out
+}
+
+pub fn shades_from_image(img: &RgbaImage) -> ShadeImage {
+    let luminance = |p: &Rgba<u8>| rgba_u8_to_rgb_f32(*p).to_linear().luminance();
+
+    // Images with exactly four colours are mapped by ranking them, so any palette works.
+    // Anything else falls back to quantizing the luminance.
+    let mut colors: Vec<Rgba<u8>> = Vec::new();
+    for p in img.pixels() {
+        if !colors.contains(p) {
+            colors.push(*p);
+            if colors.len() > 4 {
+                break;
+            }
+        }
+    }
+
+    if colors.len() == 4 {
+        colors.sort_by(|a, b| luminance(b).total_cmp(&luminance(a)));
+        ShadeImage::from_fn(img.width(), img.height(), |x, y| {
+            let p = img.get_pixel(x, y);
+            Luma([colors.iter().position(|c| c == p).unwrap() as u8])
+        })
+    } else {
+        ShadeImage::from_fn(img.width(), img.height(), |x, y| {
+            let l = to_gamma(luminance(img.get_pixel(x, y)));
+            Luma([3 - (l * 3.0).round().clamp(0.0, 3.0) as u8])
+        })
+    }
+}
+
+fn build_plain_frame() -> ShadeImage {
+    let (window_x, window_y) = (
+        (GB_FRAME_WIDTH - GB_CAMERA_WIDTH) / 2,
+        (GB_FRAME_HEIGHT - GB_CAMERA_HEIGHT) / 2,
+    );
+    ShadeImage::from_fn(GB_FRAME_WIDTH, GB_FRAME_HEIGHT, |x, y| {
+        // Outline around the photo, and a thin border near the edge of the screen
+        let (x, y) = (x as i32, y as i32);
+        let (wx, wy) = (window_x as i32, window_y as i32);
+        let (ww, wh) = (GB_CAMERA_WIDTH as i32, GB_CAMERA_HEIGHT as i32);
+        let window_outline = (x == wx - 1 || x == wx + ww) && y >= wy - 1 && y <= wy + wh
+            || (y == wy - 1 || y == wy + wh) && x >= wx - 1 && x <= wx + ww;
+        let edge = x
+            .min(y)
+            .min(GB_FRAME_WIDTH as i32 - 1 - x)
+            .min(GB_FRAME_HEIGHT as i32 - 1 - y);
+        if window_outline || edge == 4 {
+            Luma([3])
+        } else if edge < 4 {
+            Luma([2])
+        } else {
+            Luma([1])
+        }
+    })
+}
+
+fn build_film_frame() -> ShadeImage {
+    ShadeImage::from_fn(GB_FRAME_WIDTH, GB_FRAME_HEIGHT, |x, y| {
+        // Sprocket holes along the top and bottom edges
+        let in_sprocket_row = (4..10).contains(&y) || (134..140).contains(&y);
+        let in_sprocket = (x + 3) % 10 < 6;
+        if in_sprocket_row && in_sprocket {
+            Luma([0])
+        } else {
+            Luma([3])
+        }
+    })
+}
+
+pub fn gb_camera_framed(
+    view: &GbCameraView,
+    frame: &GbCameraFrame,
     adjustment: &GbColorAdjustment,
-) -> RgbaImage {
-    let (src_width, src_height) = (img.width(), img.height());
+) -> ShadeImage {
+    // The camera only dithers the photo itself, the frame is drawn with exact shades
+    let mut photo = gb_mono_quantize(&view.img, adjustment);
+    for y in 0..GB_CAMERA_HEIGHT {
+        for x in 0..GB_CAMERA_WIDTH {
+            if view.is_padding(x, y) {
+                photo.put_pixel(x, y, Luma([0]));
+            }
+        }
+    }
 
-    // Color configurations
-    let fg = Rgb::<f32>([
-        profile.foreground_r,
-        profile.foreground_g,
-        profile.foreground_b,
-    ])
-    .to_linear();
-    let fg_opacity = profile.foreground_a;
-    let bg = Rgb::<f32>([
-        profile.background_r,
-        profile.background_g,
-        profile.background_b,
-    ])
-    .to_linear();
+    let frame = match frame {
+        GbCameraFrame::None => return photo,
+        GbCameraFrame::Plain => build_plain_frame(),
+        GbCameraFrame::Film => build_film_frame(),
+        GbCameraFrame::Custom(frame) => frame.clone(),
+    };
+
+    let (window_x, window_y) = (
+        (GB_FRAME_WIDTH - GB_CAMERA_WIDTH) / 2,
+        (GB_FRAME_HEIGHT - GB_CAMERA_HEIGHT) / 2,
+    );
+    let mut out = frame;
+    for y in 0..GB_CAMERA_HEIGHT {
+        for x in 0..GB_CAMERA_WIDTH {
+            out.put_pixel(x + window_x, y + window_y, *photo.get_pixel(x, y));
+        }
+    }
+    out
+}
+
+#[inline(always)]
+pub fn decode_2bpp_pixel(data: &[u8], tile: usize, x: u32, y: u32) -> u8 {
+    // Tiles are 16 bytes of 8 rows, each row being a low and a high bit plane.
+    // Missing data decodes as colour 0.
+    let row = tile * 16 + y as usize * 2;
+    let (lo, hi) = match (data.get(row), data.get(row + 1)) {
+        (Some(lo), Some(hi)) => (*lo, *hi),
+        _ => (0, 0),
+    };
+    let bit = 7 - x;
+    ((lo >> bit) & 1) | (((hi >> bit) & 1) << 1)
+}
+
+#[inline(always)]
+pub fn apply_bgp(color: u8, bgp: u8) -> u8 {
+    (bgp >> (color * 2)) & 3
+}
+
+pub fn decode_2bpp_tiles(data: &[u8], tiles_wide: u32, tiles_high: u32) -> ShadeImage {
+    ShadeImage::from_fn(tiles_wide * 8, tiles_high * 8, |x, y| {
+        let tile = (y / 8 * tiles_wide + x / 8) as usize;
+        Luma([decode_2bpp_pixel(data, tile, x % 8, y % 8)])
+    })
+}
+
+pub fn gb_mono_quantize(img: &FloatImage, adjustment: &GbColorAdjustment) -> ShadeImage {
+    let (src_width, src_height) = (img.width(), img.height());
 
     // Quantize to alpha
     let quantized_img = AlphaImage::from_fn(src_width, src_height, |x, y| unsafe {
@@ -240,7 +572,7 @@ pub fn gb_mono(
     });
 
     // Apply adjustments
-    let mut adjusted_img = AlphaImage::new(src_width, src_height);
+    let mut adjusted_img = ShadeImage::new(src_width, src_height);
     {
         let threshold_kernel = build_threshold_kernel(adjustment);
         let edge_enhancement_level = adjustment.edge_enhancement_level;
@@ -264,9 +596,8 @@ pub fn gb_mono(
                         l -= quantized_img.unsafe_get_pixel(x, y_down)[0] * edge_enhancement_level;
                     }
 
-                    let alpha = apply_threshold_kernel(l, x, y, &threshold_kernel, &adjustment)
-                        * fg_opacity;
-                    adjusted_img.unsafe_put_pixel(x, y, Luma([alpha]));
+                    let shade = apply_threshold_kernel(l, x, y, &threshold_kernel, adjustment);
+                    adjusted_img.unsafe_put_pixel(x, y, Luma([shade]));
                 }
 
                 x_left = x;
@@ -276,15 +607,56 @@ pub fn gb_mono(
         }
     }
 
+    adjusted_img
+}
+
+pub fn gb_mono_shades(shades: &ShadeImage, profile: &GbDisplayProfile) -> RgbaImage {
+    let composite = gb_mono_composite(shades, profile);
+    RgbaImage::from_fn(composite.width(), composite.height(), |x, y| {
+        // Gamma compression
+        let color = composite.get_pixel(x, y).to_gamma();
+        Rgba([
+            float_to_byte(color[0]),
+            float_to_byte(color[1]),
+            float_to_byte(color[2]),
+            255,
+        ])
+    })
+}
+
+// Renders the LCD in linear light, with margin
+pub fn gb_mono_composite(shades: &ShadeImage, profile: &GbDisplayProfile) -> FloatImage {
+    let (src_width, src_height) = (shades.width(), shades.height());
+
+    // Color configurations
+    let fg = Rgb::<f32>([
+        profile.foreground_r,
+        profile.foreground_g,
+        profile.foreground_b,
+    ])
+    .to_linear();
+    let fg_opacity = profile.foreground_a;
+    let bg = Rgb::<f32>([
+        profile.background_r,
+        profile.background_g,
+        profile.background_b,
+    ])
+    .to_linear();
+
+    let adjusted_img = AlphaImage::from_fn(src_width, src_height, |x, y| unsafe {
+        let shade = shades.unsafe_get_pixel(x, y)[0].min(3);
+        Luma([profile.shade_opacity[shade as usize] * fg_opacity])
+    });
+
     // Don't change this without revising pretty much everything after this
-    let scale = 5;
+    let scale = LCD_SCALE;
 
     let width = src_width * scale;
     let height = src_height * scale;
 
     // Final output should have GB LCD margin
     // This margin also accounts for the edge smear and shadow blur expansion
-    let native_margin = 5;
+    let native_margin = LCD_MARGIN;
     let margin = native_margin * scale;
     let out_width = width + margin * 2;
     let out_height = height + margin * 2;
@@ -564,7 +936,7 @@ pub fn gb_mono(
     let shadow_opacity = 0.5;
     let shadow_offset = 1;
 
-    let mut out = RgbaImage::new(out_width as u32, out_height as u32);
+    let mut out = FloatImage::new(out_width as u32, out_height as u32);
     for y in 0..out_height {
         for x in 0..out_width {
             // Background shadowing
@@ -580,21 +952,56 @@ pub fn gb_mono(
             // Alpha blend foreground
             let opacity = load_alpha_checked(&fg_buff, x as i32, y as i32, out_width, out_height);
             let color = fg.mult_f(opacity).add(color.mult_f(1.0 - opacity));
-            // Gamma compression
-            let color = color.to_gamma();
             unsafe {
-                out.unsafe_put_pixel(
-                    x as u32,
-                    y as u32,
-                    Rgba([
-                        float_to_byte(color[0]),
-                        float_to_byte(color[1]),
-                        float_to_byte(color[2]),
-                        255,
-                    ]),
-                );
+                out.unsafe_put_pixel(x as u32, y as u32, color);
             }
         }
     }
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn photo() -> FloatImage {
+        FloatImage::from_pixel(64, 64, Rgb([0.5, 0.5, 0.5]))
+    }
+
+    #[test]
+    fn crop_at_the_edge_keeps_a_pixel() {
+        let crop = GbCameraCrop {
+            x: 1.0,
+            y: 1.0,
+            width: 0.5,
+            height: 0.5,
+        };
+        let view = gb_camera_view(&photo(), &crop, &GbCameraFit::Stretch);
+        assert_eq!(view.img.dimensions(), (GB_CAMERA_WIDTH, GB_CAMERA_HEIGHT));
+    }
+
+    #[test]
+    fn fit_padding_stays_lightest_when_inverted() {
+        let crop = GbCameraCrop {
+            x: 0.0,
+            y: 0.0,
+            width: 0.25,
+            height: 1.0,
+        };
+        let view = gb_camera_compose(
+            &[photo()],
+            &GbCameraLayout::Normal,
+            &crop,
+            &GbCameraFit::Fit,
+        );
+        let adjustment = GbColorAdjustment {
+            dither: false,
+            brightness: 1.0,
+            contrast: 1.0,
+            invert: true,
+            edge_enhancement_level: 0.0,
+        };
+        let shades = gb_camera_framed(&view, &GbCameraFrame::None, &adjustment);
+        assert_eq!(shades.get_pixel(0, 0)[0], 0);
+    }
+}
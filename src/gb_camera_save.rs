@@ -0,0 +1,140 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+/*
+   GB Camera SRAM layout (128 KB, 16 banks of 8 KB)
+
+   0x00000 - 0x00DFF: Last seen image from the viewfinder
+   0x011B2 - 0x011CF: Photo state vector, one byte per slot.
+                      Album index 0x00 - 0x1D, or 0xFF when the slot is deleted
+   0x02000 - 0x1FFFF: 30 photo slots of 0x1000 bytes each,
+                      starting with 0xE00 bytes of 128x112 2bpp tile data
+*/
+
+use crate::gb;
+
+use gb::*;
+
+pub const GB_CAMERA_SAVE_SIZE: usize = 0x20000;
+pub const GB_CAMERA_PHOTO_SLOTS: usize = 30;
+
+const LAST_SEEN_OFFSET: usize = 0x0000;
+const STATE_VECTOR_OFFSET: usize = 0x11B2;
+const PHOTO_OFFSET: usize = 0x2000;
+const PHOTO_SLOT_SIZE: usize = 0x1000;
+const PHOTO_DATA_SIZE: usize = 0xE00;
+
+pub enum GbCameraSlotState {
+    Active(u8),
+    Deleted,
+}
+
+pub struct GbCameraSave {
+    data: Vec<u8>,
+}
+
+impl GbCameraSave {
+    pub fn parse(data: Vec<u8>) -> Result<Self, String> {
+        // Some dumpers append RTC or header data, so only the minimum size is enforced
+        if data.len() < GB_CAMERA_SAVE_SIZE {
+            return Err(format!(
+                "GB Camera save must be at least {} bytes, got {}",
+                GB_CAMERA_SAVE_SIZE,
+                data.len()
+            ));
+        }
+        Ok(Self { data })
+    }
+
+    pub fn slot_state(&self, slot: usize) -> GbCameraSlotState {
+        match self.data[STATE_VECTOR_OFFSET + slot] {
+            index @ 0..=0x1D => GbCameraSlotState::Active(index),
+            _ => GbCameraSlotState::Deleted,
+        }
+    }
+
+    pub fn last_seen(&self) -> ShadeImage {
+        self.decode_image(LAST_SEEN_OFFSET)
+    }
+
+    pub fn photo(&self, slot: usize) -> ShadeImage {
+        self.decode_image(PHOTO_OFFSET + slot * PHOTO_SLOT_SIZE)
+    }
+
+    // Slots in album order, optionally followed by deleted slots which usually still hold image data
+    pub fn roll(&self, include_deleted: bool) -> Vec<usize> {
+        let mut active: Vec<(u8, usize)> = Vec::new();
+        let mut deleted = Vec::new();
+        for slot in 0..GB_CAMERA_PHOTO_SLOTS {
+            match self.slot_state(slot) {
+                GbCameraSlotState::Active(index) => active.push((index, slot)),
+                GbCameraSlotState::Deleted => deleted.push(slot),
+            }
+        }
+        active.sort();
+        let mut slots: Vec<usize> = active.into_iter().map(|(_, slot)| slot).collect();
+        if include_deleted {
+            slots.extend(deleted);
+        }
+        slots
+    }
+
+    fn decode_image(&self, offset: usize) -> ShadeImage {
+        decode_2bpp_tiles(
+            &self.data[offset..offset + PHOTO_DATA_SIZE],
+            GB_CAMERA_WIDTH / 8,
+            GB_CAMERA_HEIGHT / 8,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_slots_and_skips_deleted() {
+        let mut data = vec![0u8; GB_CAMERA_SAVE_SIZE];
+        data[STATE_VECTOR_OFFSET..STATE_VECTOR_OFFSET + GB_CAMERA_PHOTO_SLOTS].fill(0xFF);
+        data[STATE_VECTOR_OFFSET + 5] = 0x00;
+        data[STATE_VECTOR_OFFSET + 2] = 0x01;
+
+        // First row of the first tile in slot 5 reads 3, 2, 1, 0 from the left
+        let tile = PHOTO_OFFSET + 5 * PHOTO_SLOT_SIZE;
+        data[tile] = 0b1010_0000;
+        data[tile + 1] = 0b1100_0000;
+
+        let save = GbCameraSave::parse(data).unwrap();
+        let photo = save.photo(5);
+        assert_eq!(photo.dimensions(), (GB_CAMERA_WIDTH, GB_CAMERA_HEIGHT));
+        let row: Vec<u8> = (0..4).map(|x| photo.get_pixel(x, 0)[0]).collect();
+        assert_eq!(row, [3, 2, 1, 0]);
+        assert!(photo.pixels().skip(4).all(|p| p[0] == 0));
+
+        assert!(matches!(save.slot_state(0), GbCameraSlotState::Deleted));
+        assert!(matches!(save.slot_state(2), GbCameraSlotState::Active(1)));
+        assert_eq!(save.roll(false), [5, 2]);
+        assert_eq!(save.roll(true).len(), GB_CAMERA_PHOTO_SLOTS);
+    }
+
+    #[test]
+    fn rejects_short_saves() {
+        assert!(GbCameraSave::parse(vec![0; GB_CAMERA_SAVE_SIZE - 1]).is_err());
+    }
+}
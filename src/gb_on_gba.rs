@@ -0,0 +1,108 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{gb, shader_support};
+
+use image::Rgb;
+use shader_support::*;
+
+pub const GBA_WIDTH: u32 = 240;
+pub const GBA_HEIGHT: u32 = 160;
+
+// Every 2 columns become 3, and every 9 rows become 10
+const STRETCH_X: (u32, u32) = (2, 3);
+const STRETCH_Y: (u32, u32) = (9, 10);
+
+pub enum GbaDisplayMode {
+    // 160x144 window in the middle of the screen, with a black border
+    Centered,
+    // Stretched to fill the screen with L or R
+    Stretched,
+}
+
+impl GbaDisplayMode {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => Self::Stretched,
+            _ => Self::Centered,
+        }
+    }
+}
+
+// Source position of each output line. A line between two sources blends both.
+// The extra line of each group sits in the middle of it, between lines n / 2 - 1 and n / 2.
+fn stretch_taps(len: u32, (group, stretched): (u32, u32)) -> Vec<(u32, Option<u32>)> {
+    let inserted = group / 2;
+    (0..len / group * stretched)
+        .map(|i| {
+            let base = i / stretched * group;
+            let offset = i % stretched;
+            if offset < inserted {
+                (base + offset, None)
+            } else if offset == inserted {
+                (base + offset - 1, Some(base + offset))
+            } else {
+                (base + offset - 1, None)
+            }
+        })
+        .collect()
+}
+
+// Takes a linear image of raw colours at GB resolution, and lays it out on the GBA screen.
+// The scaler blends the digital colour values, so blending happens before linearization.
+pub fn gb_on_gba(img: &FloatImage, mode: &GbaDisplayMode) -> Result<FloatImage, String> {
+    if (img.width(), img.height()) != (gb::GB_FRAME_WIDTH, gb::GB_FRAME_HEIGHT) {
+        return Err(format!(
+            "Expected a {}x{} image, got {}x{}",
+            gb::GB_FRAME_WIDTH,
+            gb::GB_FRAME_HEIGHT,
+            img.width(),
+            img.height()
+        ));
+    }
+
+    match mode {
+        GbaDisplayMode::Centered => {
+            let (left, top) = (
+                (GBA_WIDTH - gb::GB_FRAME_WIDTH) / 2,
+                (GBA_HEIGHT - gb::GB_FRAME_HEIGHT) / 2,
+            );
+            Ok(FloatImage::from_fn(GBA_WIDTH, GBA_HEIGHT, |x, y| {
+                if x < left || y < top || x >= left + img.width() || y >= top + img.height() {
+                    Rgb([0.0, 0.0, 0.0])
+                } else {
+                    *img.get_pixel(x - left, y - top)
+                }
+            }))
+        }
+        GbaDisplayMode::Stretched => {
+            let columns = stretch_taps(img.width(), STRETCH_X);
+            let rows = stretch_taps(img.height(), STRETCH_Y);
+            let sample = |x: u32, y: u32| img.get_pixel(x, y).to_gamma();
+            let blend = |(a, b): (u32, Option<u32>), at: &dyn Fn(u32) -> Rgb<f32>| match b {
+                Some(b) => lerp_color(at(a), at(b), 0.5),
+                None => at(a),
+            };
+            Ok(FloatImage::from_fn(GBA_WIDTH, GBA_HEIGHT, |x, y| {
+                let row = |sy: u32| blend(columns[x as usize], &|sx| sample(sx, sy));
+                blend(rows[y as usize], &row).to_linear()
+            }))
+        }
+    }
+}
@@ -0,0 +1,390 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{gb, shader_support};
+
+use gb::{GbDisplayProfile, ShadeImage, DEFAULT_SHADE_OPACITY};
+use image::{Luma, Rgb};
+use shader_support::*;
+
+// Samples of the LCD response table, over opacities from 0 to 1
+const RESPONSE_STEPS: usize = 64;
+
+// Size in GB pixels of the patch rendered for the response table
+const RESPONSE_PATCH: u32 = 8;
+
+// Only the middle of each band is sampled, away from the edges and the bezel
+const BAND_INSET: f32 = 0.25;
+const CROSS_INSET: f32 = 0.1;
+const BLOCKS_ALONG: u32 = 3;
+const BLOCKS_ACROSS: u32 = 6;
+
+// Fits this close to the best one are treated as equally good
+const TIE_TOLERANCE: f32 = 0.005;
+
+// Tolerance for foreground and background colours just outside the displayable range
+const GAMUT_SLACK: f32 = 0.01;
+
+pub struct PaletteEstimate {
+    pub profile: GbDisplayProfile,
+    // 0 is a guess, 1 means the photo matches the LCD model closely
+    pub confidence: f32,
+    // Shades whose opacity was measured, the others keep their defaults
+    pub estimated_shades: [bool; 4],
+}
+
+struct Band {
+    mean: Rgb<f32>,
+    deviation: f32,
+}
+
+// Average share of the foreground and background in one LCD pixel, at a given opacity.
+// Rendering a small patch keeps this in sync with the pixel grid, smear and shadow of gb_mono.
+struct LcdResponse {
+    foreground: Vec<f32>,
+    background: Vec<f32>,
+}
+
+impl LcdResponse {
+    fn new() -> Self {
+        // Foreground goes to red, background to green, so one render gives both weights
+        let shades = ShadeImage::from_pixel(RESPONSE_PATCH, RESPONSE_PATCH, Luma([0]));
+        let mut foreground = Vec::with_capacity(RESPONSE_STEPS + 1);
+        let mut background = Vec::with_capacity(RESPONSE_STEPS + 1);
+        for i in 0..=RESPONSE_STEPS {
+            let profile = GbDisplayProfile {
+                foreground_r: 1.0,
+                foreground_g: 0.0,
+                foreground_b: 0.0,
+                foreground_a: 1.0,
+                background_r: 0.0,
+                background_g: 1.0,
+                background_b: 0.0,
+                shade_opacity: [i as f32 / RESPONSE_STEPS as f32; 4],
+            };
+            let img = gb::gb_mono_composite(&shades, &profile);
+
+            // Whole pixel periods from the middle of the patch, clear of the margin and the smear
+            // of its edges
+            let start = (gb::LCD_MARGIN + 2) * gb::LCD_SCALE;
+            let end = (gb::LCD_MARGIN + RESPONSE_PATCH - 1) * gb::LCD_SCALE;
+            let mut sum = Rgb([0.0, 0.0, 0.0]);
+            for y in start..end {
+                for x in start..end {
+                    sum = sum.add(*img.get_pixel(x, y));
+                }
+            }
+            let sum = sum.div_f(((end - start) * (end - start)) as f32);
+            foreground.push(sum[0]);
+            background.push(sum[1]);
+        }
+        LcdResponse {
+            foreground,
+            background,
+        }
+    }
+
+    fn weights(&self, opacity: f32) -> (f32, f32) {
+        let t = opacity.clamp(0.0, 1.0) * RESPONSE_STEPS as f32;
+        let i = (t as usize).min(RESPONSE_STEPS - 1);
+        let f = t - i as f32;
+        (
+            self.foreground[i] + (self.foreground[i + 1] - self.foreground[i]) * f,
+            self.background[i] + (self.background[i + 1] - self.background[i]) * f,
+        )
+    }
+
+    fn color(&self, fg: Rgb<f32>, bg: Rgb<f32>, opacity: f32) -> Rgb<f32> {
+        let (wf, wb) = self.weights(opacity);
+        fg.mult_f(wf).add(bg.mult_f(wb))
+    }
+}
+
+fn distance(a: Rgb<f32>, b: Rgb<f32>) -> f32 {
+    let d = a.add(b.mult_f(-1.0));
+    (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt()
+}
+
+// Four bands of equal size, either side by side or stacked, ordered from lightest to darkest
+fn measure_bands(photo: &FloatImage) -> [Band; 4] {
+    let (width, height) = (photo.width(), photo.height());
+    let luminance_profile = |along_x: bool| -> f32 {
+        let (len, cross) = if along_x {
+            (width, height)
+        } else {
+            (height, width)
+        };
+        let cross_range =
+            (cross as f32 * CROSS_INSET) as u32..(cross as f32 * (1.0 - CROSS_INSET)) as u32;
+        let means: Vec<f32> = (0..len)
+            .map(|i| {
+                let sum: f32 = cross_range
+                    .clone()
+                    .map(|j| {
+                        let (x, y) = if along_x { (i, j) } else { (j, i) };
+                        photo.get_pixel(x, y).luminance()
+                    })
+                    .sum();
+                sum / cross_range.len().max(1) as f32
+            })
+            .collect();
+        let max = means.iter().copied().fold(f32::MIN, f32::max);
+        let min = means.iter().copied().fold(f32::MAX, f32::min);
+        max - min
+    };
+    let along_x = luminance_profile(true) >= luminance_profile(false);
+
+    let (len, cross) = if along_x {
+        (width as f32, height as f32)
+    } else {
+        (height as f32, width as f32)
+    };
+    let mut bands: Vec<Band> = (0..4)
+        .map(|k| {
+            let band_len = len / 4.0;
+            let start = (band_len * (k as f32 + BAND_INSET)) as u32;
+            let end = ((band_len * (k as f32 + 1.0 - BAND_INSET)) as u32).max(start + 1);
+            let cross_start = (cross * CROSS_INSET) as u32;
+            let cross_end = ((cross * (1.0 - CROSS_INSET)) as u32).max(cross_start + 1);
+
+            // Noise is measured between blocks, so the pixel grid itself does not count
+            let mut blocks = Vec::new();
+            let mut sum = Rgb([0.0, 0.0, 0.0]);
+            for bi in 0..BLOCKS_ALONG {
+                for bj in 0..BLOCKS_ACROSS {
+                    let i_range = start + (end - start) * bi / BLOCKS_ALONG
+                        ..start + ((end - start) * (bi + 1) / BLOCKS_ALONG).max(1);
+                    let j_range = cross_start + (cross_end - cross_start) * bj / BLOCKS_ACROSS
+                        ..cross_start
+                            + ((cross_end - cross_start) * (bj + 1) / BLOCKS_ACROSS).max(1);
+                    let mut block = Rgb([0.0, 0.0, 0.0]);
+                    let mut count = 0;
+                    for i in i_range {
+                        for j in j_range.clone() {
+                            let (x, y) = if along_x { (i, j) } else { (j, i) };
+                            block = block.add(*photo.get_pixel(x, y));
+                            count += 1;
+                        }
+                    }
+                    let block = block.div_f(count.max(1) as f32);
+                    sum = sum.add(block);
+                    blocks.push(block);
+                }
+            }
+            let mean = sum.div_f(blocks.len() as f32);
+            let variance = blocks
+                .iter()
+                .map(|c| distance(*c, mean).powi(2))
+                .sum::<f32>()
+                / blocks.len() as f32;
+            Band {
+                mean,
+                deviation: variance.sqrt(),
+            }
+        })
+        .collect();
+    bands.sort_by(|a, b| b.mean.luminance().total_cmp(&a.mean.luminance()));
+
+    let mut it = bands.into_iter();
+    std::array::from_fn(|_| it.next().unwrap())
+}
+
+// Opacity along the curve between foreground and background that best matches a colour
+fn closest_opacity(
+    response: &LcdResponse,
+    fg: Rgb<f32>,
+    bg: Rgb<f32>,
+    color: Rgb<f32>,
+    max: f32,
+) -> (f32, f32) {
+    let error = |a: f32| distance(response.color(fg, bg, a), color);
+    // Coarse search, then refine around the best step
+    let steps = 100;
+    let mut best = 0.0;
+    for i in 0..=steps {
+        let a = max * i as f32 / steps as f32;
+        if error(a) < error(best) {
+            best = a;
+        }
+    }
+    let (mut lo, mut hi) = (
+        (best - max / steps as f32).max(0.0),
+        (best + max / steps as f32).min(max),
+    );
+    for _ in 0..30 {
+        let m1 = lo + (hi - lo) / 3.0;
+        let m2 = hi - (hi - lo) / 3.0;
+        if error(m1) < error(m2) {
+            hi = m2;
+        } else {
+            lo = m1;
+        }
+    }
+    let a = (lo + hi) * 0.5;
+    (a, error(a))
+}
+
+// Estimates a GB palette from a photo of the screen showing all four shades in bands.
+// The photo should be cropped to the screen. Only the opacities of shades 1 and 2 are
+// estimated. The lightest and darkest shades keep their default opacities, since a
+// change to them is mostly absorbed by a different background and foreground, so the
+// photo says little about them.
+pub fn estimate_gb_palette(photo: &FloatImage) -> Result<PaletteEstimate, String> {
+    if photo.width() < 16 || photo.height() < 16 {
+        return Err("Photo is too small to find the shade bands".to_string());
+    }
+
+    let bands = measure_bands(photo);
+    let colors: Vec<Rgb<f32>> = bands.iter().map(|b| b.mean).collect();
+    let span = distance(colors[0], colors[3]);
+    if span < 0.01 {
+        return Err("Could not find four distinct shades in the photo".to_string());
+    }
+
+    let response = LcdResponse::new();
+
+    // Search the foreground opacity. For each, the lightest and darkest shades fix the
+    // foreground and background, and the middle shades show how well the model fits.
+    struct Candidate {
+        fg: Rgb<f32>,
+        bg: Rgb<f32>,
+        fg_alpha: f32,
+        opacity: [f32; 2],
+        error: f32,
+        in_gamut: bool,
+    }
+    let mut candidates = Vec::new();
+    for step in 20..=100 {
+        let fg_alpha = step as f32 / 100.0;
+        let (f0, b0) = response.weights(DEFAULT_SHADE_OPACITY[0] * fg_alpha);
+        let (f3, b3) = response.weights(DEFAULT_SHADE_OPACITY[3] * fg_alpha);
+        let det = f0 * b3 - f3 * b0;
+        if det.abs() < 1e-6 {
+            continue;
+        }
+        let solve = |c0: f32, c3: f32| ((c0 * b3 - c3 * b0) / det, (f0 * c3 - f3 * c0) / det);
+        let mut fg = Rgb([0.0; 3]);
+        let mut bg = Rgb([0.0; 3]);
+        for c in 0..3 {
+            let (f, b) = solve(colors[0][c], colors[3][c]);
+            fg[c] = f;
+            bg[c] = b;
+        }
+        let in_gamut = (0..3).all(|c| {
+            (-GAMUT_SLACK..=1.0 + GAMUT_SLACK).contains(&fg[c])
+                && (-GAMUT_SLACK..=1.0 + GAMUT_SLACK).contains(&bg[c])
+        });
+
+        let (a1, e1) = closest_opacity(&response, fg, bg, colors[1], fg_alpha);
+        let (a2, e2) = closest_opacity(&response, fg, bg, colors[2], fg_alpha);
+        candidates.push(Candidate {
+            fg,
+            bg,
+            fg_alpha,
+            opacity: [a1 / fg_alpha, a2 / fg_alpha],
+            error: (e1 * e1 + e2 * e2).sqrt(),
+            in_gamut,
+        });
+    }
+
+    // Colours the screen can show win over a closer fit that cannot be displayed
+    if candidates.iter().any(|c| c.in_gamut) {
+        candidates.retain(|c| c.in_gamut);
+    }
+    let min_error = candidates.iter().map(|c| c.error).fold(f32::MAX, f32::min);
+    // A paler foreground at lower opacity looks much the same, so ties go to the most opaque one
+    let best = candidates
+        .into_iter()
+        .filter(|c| c.error <= min_error + TIE_TOLERANCE * span)
+        .max_by(|a, b| a.fg_alpha.total_cmp(&b.fg_alpha))
+        .ok_or("Could not fit the LCD model to the photo".to_string())?;
+
+    // How well the middle shades sit on the model curve, relative to the full range
+    let fit = 1.0 - (best.error / span * 5.0).clamp(0.0, 1.0);
+    // How noisy the bands are, relative to the smallest step between shades
+    let min_gap = (0..3)
+        .map(|k| distance(colors[k], colors[k + 1]))
+        .fold(f32::MAX, f32::min);
+    let deviation = bands.iter().map(|b| b.deviation).sum::<f32>() / 4.0;
+    let clean = 1.0 - (deviation / min_gap.max(1e-6)).clamp(0.0, 1.0);
+    let ordered = best.opacity[0] < best.opacity[1];
+    let confidence =
+        fit * clean * if best.in_gamut { 1.0 } else { 0.5 } * if ordered { 1.0 } else { 0.5 };
+
+    let fg = best.fg.clamp01().to_gamma();
+    let bg = best.bg.clamp01().to_gamma();
+    Ok(PaletteEstimate {
+        profile: GbDisplayProfile {
+            foreground_r: fg[0],
+            foreground_g: fg[1],
+            foreground_b: fg[2],
+            foreground_a: best.fg_alpha,
+            background_r: bg[0],
+            background_g: bg[1],
+            background_b: bg[2],
+            shade_opacity: [
+                DEFAULT_SHADE_OPACITY[0],
+                best.opacity[0],
+                best.opacity[1],
+                DEFAULT_SHADE_OPACITY[3],
+            ],
+        },
+        confidence,
+        estimated_shades: [false, true, true, false],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_rendered_palette() {
+        let fg = Rgb([0.12, 0.30, 0.05]);
+        let bg = Rgb([0.65, 0.70, 0.12]);
+        let opacity = [DEFAULT_SHADE_OPACITY[0], 0.4, 0.7, DEFAULT_SHADE_OPACITY[3]];
+
+        // Four bands of flat colour, as the LCD model shows each shade on average
+        let response = LcdResponse::new();
+        let (fg_linear, bg_linear) = (fg.to_linear(), bg.to_linear());
+        let photo = FloatImage::from_fn(160, 144, |x, _| {
+            response.color(fg_linear, bg_linear, opacity[(x / 40) as usize])
+        });
+
+        let estimate = estimate_gb_palette(&photo).unwrap();
+        let p = &estimate.profile;
+        let close = |a: f32, b: f32| (a - b).abs() < 0.02;
+        assert!(close(p.foreground_a, 1.0));
+        for (actual, expected) in [
+            (p.foreground_r, fg[0]),
+            (p.foreground_g, fg[1]),
+            (p.foreground_b, fg[2]),
+            (p.background_r, bg[0]),
+            (p.background_g, bg[1]),
+            (p.background_b, bg[2]),
+        ] {
+            assert!(close(actual, expected), "{} != {}", actual, expected);
+        }
+        for (actual, expected) in p.shade_opacity.iter().zip(opacity) {
+            assert!(close(*actual, expected), "{} != {}", actual, expected);
+        }
+        assert!(estimate.confidence > 0.9, "{}", estimate.confidence);
+        assert_eq!(estimate.estimated_shades, [false, true, true, false]);
+    }
+}
@@ -0,0 +1,443 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+/*
+   Game Boy Printer packet format
+
+   0x88 0x33 | command | compression | length (LE16) | data | checksum (LE16) | [keep alive | status]
+
+   The checksum is the 16 bit sum of every byte from the command to the end of data.
+   Capture logs usually include the two response bytes from the printer, but not always.
+   https://gbdev.io/pandocs/Gameboy_Printer.html
+*/
+
+use crate::{gb, shader_support};
+
+use gb::*;
+use image::{Rgb, Rgba, RgbaImage};
+use shader_support::*;
+use std::fmt;
+
+pub const PRINTER_WIDTH: u32 = 160;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_BREAK: u8 = 0x08;
+const COMMAND_INQUIRY: u8 = 0x0F;
+
+// Printer feed for one unit of margin, roughly a tile row
+const MARGIN_UNIT_LINES: u32 = 8;
+
+pub enum PrinterIssue {
+    InvalidHex {
+        line: usize,
+        token: String,
+    },
+    Truncated {
+        offset: usize,
+    },
+    ChecksumMismatch {
+        offset: usize,
+        expected: u16,
+        actual: u16,
+    },
+    UnknownCommand {
+        offset: usize,
+        command: u8,
+    },
+    InvalidCompression {
+        offset: usize,
+    },
+    InvalidPrint {
+        offset: usize,
+        length: usize,
+    },
+}
+
+impl fmt::Display for PrinterIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidHex { line, token } => {
+                write!(f, "Line {}: invalid hex byte \"{}\"", line, token)
+            }
+            Self::Truncated { offset } => write!(f, "Byte {}: packet is truncated", offset),
+            Self::ChecksumMismatch {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Byte {}: checksum mismatch, expected {:04X} but packet has {:04X}",
+                offset, expected, actual
+            ),
+            Self::UnknownCommand { offset, command } => {
+                write!(f, "Byte {}: unknown command {:02X}", offset, command)
+            }
+            Self::InvalidCompression { offset } => {
+                write!(f, "Byte {}: compressed data ends mid run", offset)
+            }
+            Self::InvalidPrint { offset, length } => write!(
+                f,
+                "Byte {}: print command has {} bytes of data, expected 4",
+                offset, length
+            ),
+        }
+    }
+}
+
+pub struct PrintedImage {
+    pub shades: ShadeImage,
+    pub margin_before: u8,
+    pub margin_after: u8,
+    pub exposure: u8,
+}
+
+pub struct PrinterLog {
+    pub images: Vec<PrintedImage>,
+    pub issues: Vec<PrinterIssue>,
+}
+
+fn parse_hex_log(log: &str, issues: &mut Vec<PrinterIssue>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (line_index, line) in log.lines().enumerate() {
+        // Comments, and JSON status lines some capture tools print between packets
+        let line = line.split("//").next().unwrap_or("");
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.starts_with('{') || line.starts_with('!') {
+            continue;
+        }
+
+        for token in line.split(|c: char| c.is_whitespace() || c == ',') {
+            let token = token.trim_start_matches("0x").trim_start_matches("0X");
+            if token.is_empty() {
+                continue;
+            }
+            // Also accept runs of hex without separators
+            if token.len() % 2 != 0 || !token.chars().all(|c| c.is_ascii_hexdigit()) {
+                issues.push(PrinterIssue::InvalidHex {
+                    line: line_index + 1,
+                    token: token.to_string(),
+                });
+                continue;
+            }
+            for i in (0..token.len()).step_by(2) {
+                bytes.push(u8::from_str_radix(&token[i..i + 2], 16).unwrap());
+            }
+        }
+    }
+    bytes
+}
+
+fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    // Control byte with the top bit set repeats the next byte (n & 0x7F) + 2 times,
+    // otherwise the next n + 1 bytes are copied as is.
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            let value = *data.get(i)?;
+            i += 1;
+            out.extend(std::iter::repeat_n(value, (control & 0x7F) as usize + 2));
+        } else {
+            let len = control as usize + 1;
+            out.extend_from_slice(data.get(i..i + len)?);
+            i += len;
+        }
+    }
+    Some(out)
+}
+
+fn build_printed_image(buffer: &[u8], palette: u8) -> ShadeImage {
+    // Palette 0 is treated as the default palette by the printer
+    let palette = if palette == 0 { 0xE4 } else { palette };
+    let tiles_wide = PRINTER_WIDTH / 8;
+    let tiles_high = (buffer.len() as u32).div_ceil(tiles_wide * 16);
+    let mut shades = decode_2bpp_tiles(buffer, tiles_wide, tiles_high);
+    for p in shades.pixels_mut() {
+        p[0] = apply_bgp(p[0], palette);
+    }
+    shades
+}
+
+fn append_rows(dst: &mut ShadeImage, src: &ShadeImage) {
+    let mut out = ShadeImage::new(PRINTER_WIDTH, dst.height() + src.height());
+    for (x, y, p) in dst.enumerate_pixels() {
+        out.put_pixel(x, y, *p);
+    }
+    for (x, y, p) in src.enumerate_pixels() {
+        out.put_pixel(x, y + dst.height(), *p);
+    }
+    *dst = out;
+}
+
+pub fn decode_printer_log(log: &str) -> PrinterLog {
+    let mut issues = Vec::new();
+    let bytes = parse_hex_log(log, &mut issues);
+
+    let mut images: Vec<PrintedImage> = Vec::new();
+    let mut buffer: Vec<u8> = Vec::new();
+    // Prints without a margin in between continue the previous image, like on paper
+    let mut continue_previous = false;
+
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        if bytes[i] != 0x88 || bytes[i + 1] != 0x33 {
+            i += 1;
+            continue;
+        }
+
+        let offset = i;
+        let Some(header) = bytes.get(i + 2..i + 6) else {
+            issues.push(PrinterIssue::Truncated { offset });
+            break;
+        };
+        let (command, compression) = (header[0], header[1]);
+        let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+
+        let data_start = i + 6;
+        let Some(packet) = bytes.get(data_start..data_start + length + 2) else {
+            issues.push(PrinterIssue::Truncated { offset });
+            // Resynchronize on the next packet
+            i += 2;
+            continue;
+        };
+        let data = &packet[..length];
+        let actual = u16::from_le_bytes([packet[length], packet[length + 1]]);
+        let expected = header
+            .iter()
+            .chain(data.iter())
+            .fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+
+        i = data_start + length + 2;
+        // Skip the printer response if it has been logged
+        if i + 1 < bytes.len() && !(bytes[i] == 0x88 && bytes[i + 1] == 0x33) {
+            i += 2;
+        }
+
+        if expected != actual {
+            issues.push(PrinterIssue::ChecksumMismatch {
+                offset,
+                expected,
+                actual,
+            });
+            continue;
+        }
+
+        match command {
+            COMMAND_INIT => buffer.clear(),
+            COMMAND_DATA => {
+                if compression != 0 {
+                    match decompress(data) {
+                        Some(data) => buffer.extend_from_slice(&data),
+                        None => issues.push(PrinterIssue::InvalidCompression { offset }),
+                    }
+                } else {
+                    buffer.extend_from_slice(data);
+                }
+            }
+            COMMAND_PRINT => {
+                if length != 4 {
+                    issues.push(PrinterIssue::InvalidPrint { offset, length });
+                    continue;
+                }
+                let (margins, palette, exposure) = (data[1], data[2], data[3]);
+                if buffer.is_empty() {
+                    continue;
+                }
+                let shades = build_printed_image(&buffer, palette);
+                buffer.clear();
+
+                let margin_before = margins >> 4;
+                let margin_after = margins & 0x0F;
+                match images.last_mut() {
+                    Some(last) if continue_previous && margin_before == 0 => {
+                        append_rows(&mut last.shades, &shades);
+                        last.margin_after = margin_after;
+                    }
+                    _ => images.push(PrintedImage {
+                        shades,
+                        margin_before,
+                        margin_after,
+                        exposure,
+                    }),
+                }
+                continue_previous = margin_after == 0;
+            }
+            COMMAND_BREAK => buffer.clear(),
+            // Status requests don't change what is printed
+            COMMAND_INQUIRY => {}
+            _ => issues.push(PrinterIssue::UnknownCommand { offset, command }),
+        }
+    }
+
+    PrinterLog { images, issues }
+}
+
+pub fn printer_render(printed: &PrintedImage) -> RgbaImage {
+    // Thermal paper look: round dots which grow with darkness, on slightly warm paper
+    let scale = 4;
+    let paper = Rgb::<f32>([0.95, 0.94, 0.90]).to_linear();
+    let ink = Rgb::<f32>([0.10, 0.10, 0.12]).to_linear();
+
+    // Lower 7 bits of exposure go from -25% to +25% darkness, 0x40 being the default
+    let exposure = 0.75 + 0.5 * (printed.exposure & 0x7F) as f32 / 127.0;
+
+    let (src_width, src_height) = printed.shades.dimensions();
+    let top = printed.margin_before as u32 * MARGIN_UNIT_LINES;
+    let bottom = printed.margin_after as u32 * MARGIN_UNIT_LINES;
+    let width = src_width * scale;
+    let height = (src_height + top + bottom) * scale;
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let (cell_x, cell_y) = (x / scale, (y / scale) as i32 - top as i32);
+        let darkness = if cell_y < 0 || cell_y >= src_height as i32 {
+            0.0
+        } else {
+            let shade = printed.shades.get_pixel(cell_x, cell_y as u32)[0];
+            let darkness = (shade as f32 / 3.0 * exposure).min(1.0);
+            let dx = (x % scale) as f32 + 0.5 - scale as f32 * 0.5;
+            let dy = (y % scale) as f32 + 0.5 - scale as f32 * 0.5;
+            let dist = (dx * dx + dy * dy).sqrt() / scale as f32;
+            let radius = 0.35 + 0.45 * darkness;
+            darkness * clamp((radius - dist) * 6.0 + 0.5, 0.0, 1.0)
+        };
+        let color = lerp_color(paper, ink, darkness).to_gamma();
+        Rgba([
+            float_to_byte(color[0]),
+            float_to_byte(color[1]),
+            float_to_byte(color[2]),
+            255,
+        ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tile data for one row of 20 tiles, one packet's worth of a print
+    const ROW_BYTES: usize = 320;
+
+    fn packet(command: u8, compression: u8, data: &[u8]) -> Vec<u8> {
+        let mut body = vec![command, compression];
+        body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        body.extend_from_slice(data);
+        let checksum = body.iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+        let mut out = vec![0x88, 0x33];
+        out.extend(body);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        // Keep alive and status from the printer
+        out.extend_from_slice(&[0x81, 0x00]);
+        out
+    }
+
+    fn print(palette: u8) -> Vec<u8> {
+        packet(COMMAND_PRINT, 0, &[0x01, 0x00, palette, 0x40])
+    }
+
+    fn to_log(bytes: &[u8]) -> String {
+        bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn all_shades(img: &ShadeImage, shade: u8) -> bool {
+        img.pixels().all(|p| p[0] == shade)
+    }
+
+    #[test]
+    fn decompress_runs() {
+        assert_eq!(
+            decompress(&[0x01, 0xAA, 0xBB, 0x82, 0xCC]),
+            Some(vec![0xAA, 0xBB, 0xCC, 0xCC, 0xCC, 0xCC])
+        );
+        assert_eq!(decompress(&[0x02, 0xAA]), None);
+        assert_eq!(decompress(&[0x80]), None);
+    }
+
+    #[test]
+    fn compressed_data_packet() {
+        // 2 literal bytes, then runs of 129, 129 and 60
+        let compressed = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xBA, 0xFF];
+        let mut bytes = packet(COMMAND_INIT, 0, &[]);
+        bytes.extend(packet(COMMAND_DATA, 1, &compressed));
+        bytes.extend(print(0xE4));
+
+        let log = decode_printer_log(&to_log(&bytes));
+        assert!(log.issues.is_empty());
+        assert_eq!(log.images.len(), 1);
+        let shades = &log.images[0].shades;
+        assert_eq!(shades.dimensions(), (PRINTER_WIDTH, 8));
+        assert!(all_shades(shades, 3));
+    }
+
+    #[test]
+    fn checksum_mismatch_is_reported() {
+        let mut data = packet(COMMAND_DATA, 0, &[0xFF; ROW_BYTES]);
+        let checksum_at = data.len() - 4;
+        data[checksum_at] ^= 0x01;
+        let mut bytes = packet(COMMAND_INIT, 0, &[]);
+        let offset = bytes.len();
+        bytes.extend(data);
+        bytes.extend(print(0xE4));
+
+        let log = decode_printer_log(&to_log(&bytes));
+        assert!(log.images.is_empty());
+        assert_eq!(log.issues.len(), 1);
+        assert!(matches!(
+            log.issues[0],
+            PrinterIssue::ChecksumMismatch { offset: o, expected, actual }
+                if o == offset && expected != actual
+        ));
+    }
+
+    #[test]
+    fn resyncs_after_truncated_packet() {
+        // Garbage, then a data packet claiming 640 bytes which are never sent
+        let mut bytes = vec![0x12, 0x34, 0x88, 0x88, 0x33, COMMAND_DATA, 0x00, 0x80, 0x02];
+        bytes.extend(packet(COMMAND_INIT, 0, &[]));
+        bytes.extend(packet(COMMAND_DATA, 0, &[0xFF; ROW_BYTES]));
+        bytes.extend(print(0xE4));
+
+        let log = decode_printer_log(&to_log(&bytes));
+        assert_eq!(log.issues.len(), 1);
+        assert!(matches!(
+            log.issues[0],
+            PrinterIssue::Truncated { offset: 3 }
+        ));
+        assert_eq!(log.images.len(), 1);
+        assert!(all_shades(&log.images[0].shades, 3));
+    }
+
+    #[test]
+    fn palette_zero_is_default() {
+        let mut bytes = packet(COMMAND_INIT, 0, &[]);
+        bytes.extend(packet(COMMAND_DATA, 0, &[0xFF; ROW_BYTES]));
+        bytes.extend(print(0x00));
+
+        let log = decode_printer_log(&to_log(&bytes));
+        assert_eq!(log.images.len(), 1);
+        assert!(all_shades(&log.images[0].shades, 3));
+    }
+}
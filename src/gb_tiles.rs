@@ -0,0 +1,151 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::gb;
+
+use gb::*;
+use image::Luma;
+
+pub const TILEMAP_SIZE: usize = 32 * 32;
+// Largest output on either side, well beyond the 256x256 background map
+pub const MAX_SIZE: u32 = 2048;
+
+pub struct TileLayout {
+    pub width: u32,
+    pub height: u32,
+    pub scroll_x: u32,
+    pub scroll_y: u32,
+    // 0x8800 addressing, where the tile data blob is expected to start at 0x8000
+    pub signed_addressing: bool,
+}
+
+pub fn render_tiles(
+    tiles: &[u8],
+    tilemap: Option<&[u8]>,
+    bgp: u8,
+    layout: &TileLayout,
+) -> ShadeImage {
+    ShadeImage::from_fn(layout.width, layout.height, |x, y| {
+        let color = match tilemap {
+            Some(tilemap) => {
+                // The background map wraps around at 256 pixels like on hardware
+                let map_x = (x % 256 + layout.scroll_x % 256) % 256;
+                let map_y = (y % 256 + layout.scroll_y % 256) % 256;
+                let index = tilemap[(map_y / 8 * 32 + map_x / 8) as usize];
+                let tile = if layout.signed_addressing {
+                    (256 + index as i8 as i32) as usize
+                } else {
+                    index as usize
+                };
+                decode_2bpp_pixel(tiles, tile, map_x % 8, map_y % 8)
+            }
+            None => {
+                // Without a tilemap, tiles are laid out in order like a VRAM viewer
+                let tiles_wide = layout.width.div_ceil(8);
+                let tile = (y / 8 * tiles_wide + x / 8) as usize;
+                decode_2bpp_pixel(tiles, tile, x % 8, y % 8)
+            }
+        };
+        Luma([apply_bgp(color, bgp)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTITY_BGP: u8 = 0xE4;
+
+    // Tile data where every listed tile is filled with a single colour
+    fn solid_tiles(count: usize, colors: &[(usize, u8)]) -> Vec<u8> {
+        let mut tiles = vec![0u8; count * 16];
+        for &(tile, color) in colors {
+            for row in 0..8 {
+                tiles[tile * 16 + row * 2] = if color & 1 != 0 { 0xFF } else { 0 };
+                tiles[tile * 16 + row * 2 + 1] = if color & 2 != 0 { 0xFF } else { 0 };
+            }
+        }
+        tiles
+    }
+
+    fn layout(width: u32, height: u32, scroll_x: u32, signed_addressing: bool) -> TileLayout {
+        TileLayout {
+            width,
+            height,
+            scroll_x,
+            scroll_y: 0,
+            signed_addressing,
+        }
+    }
+
+    #[test]
+    fn signed_addressing() {
+        let tiles = solid_tiles(384, &[(0, 3), (128, 1), (256, 2)]);
+        let mut tilemap = vec![0u8; TILEMAP_SIZE];
+        tilemap[0] = 0x80;
+        tilemap[1] = 0x00;
+
+        let signed = render_tiles(
+            &tiles,
+            Some(&tilemap),
+            IDENTITY_BGP,
+            &layout(16, 8, 0, true),
+        );
+        assert_eq!(signed.get_pixel(0, 0)[0], 1);
+        assert_eq!(signed.get_pixel(8, 0)[0], 2);
+
+        let unsigned = render_tiles(
+            &tiles,
+            Some(&tilemap),
+            IDENTITY_BGP,
+            &layout(16, 8, 0, false),
+        );
+        assert_eq!(unsigned.get_pixel(0, 0)[0], 1);
+        assert_eq!(unsigned.get_pixel(8, 0)[0], 3);
+    }
+
+    #[test]
+    fn scroll_wraps_at_256() {
+        let tiles = solid_tiles(2, &[(1, 3)]);
+        let mut tilemap = vec![0u8; TILEMAP_SIZE];
+        tilemap[0] = 1;
+
+        for scroll_x in [252, 252 + 256, u32::MAX - 3] {
+            let img = render_tiles(
+                &tiles,
+                Some(&tilemap),
+                IDENTITY_BGP,
+                &layout(8, 8, scroll_x, false),
+            );
+            let row: Vec<u8> = (0..8).map(|x| img.get_pixel(x, 0)[0]).collect();
+            assert_eq!(row, [0, 0, 0, 0, 3, 3, 3, 3], "scroll {}", scroll_x);
+        }
+    }
+
+    #[test]
+    fn tiles_in_order_without_tilemap() {
+        let tiles = solid_tiles(3, &[(0, 1), (1, 2), (2, 3)]);
+        let img = render_tiles(&tiles, None, IDENTITY_BGP, &layout(16, 16, 0, false));
+        assert_eq!(img.get_pixel(0, 0)[0], 1);
+        assert_eq!(img.get_pixel(8, 0)[0], 2);
+        assert_eq!(img.get_pixel(0, 8)[0], 3);
+        // Past the end of the tile data
+        assert_eq!(img.get_pixel(8, 8)[0], 0);
+    }
+}
@@ -17,11 +17,81 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::colorimetry;
+use crate::lut;
 use crate::shader_support;
 
+use colorimetry::PanelMeasurement;
 use image::{GenericImage, GenericImageView, Rgb, Rgba, RgbaImage};
+use lut::*;
 use shader_support::*;
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum PanelLighting {
+    Backlit,
+    // Lit only by ambient light, like the GBC and the original GBA
+    Reflective,
+    // Reflective, with an optional edge light like the GBA SP AGS-001
+    FrontLit,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SubpixelOrder {
+    Rgb,
+    Bgr,
+}
+
+impl SubpixelOrder {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => SubpixelOrder::Bgr,
+            _ => SubpixelOrder::Rgb,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SubpixelOrientation {
+    // Subpixels sit side by side, each one a vertical stripe
+    Vertical,
+    // Subpixels are stacked top to bottom, as on a panel mounted sideways
+    Horizontal,
+}
+
+impl SubpixelOrientation {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => SubpixelOrientation::Horizontal,
+            _ => SubpixelOrientation::Vertical,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct SubpixelLayout {
+    pub order: SubpixelOrder,
+    pub orientation: SubpixelOrientation,
+    // Fraction of each subpixel that emits light, the rest is the gap to the next one
+    pub fill: f32,
+}
+
+impl SubpixelLayout {
+    pub const RGB: Self = SubpixelLayout {
+        order: SubpixelOrder::Rgb,
+        orientation: SubpixelOrientation::Vertical,
+        fill: 1.0,
+    };
+    pub const BGR: Self = SubpixelLayout {
+        order: SubpixelOrder::Bgr,
+        orientation: SubpixelOrientation::Vertical,
+        fill: 1.0,
+    };
+}
+
+// Colour correction input is decoded from sRGB, which is close to this gamma
+const SOURCE_GAMMA: f32 = 2.2;
+
+#[derive(Clone, Copy)]
 pub struct DisplayProfile {
     pub r: f32,
     pub gr: f32,
@@ -35,7 +105,440 @@ pub struct DisplayProfile {
     pub lum: f32,
     pub gamma: f32,
     pub gamma_offset: f32,
-    pub bgr: bool,
+    pub subpixel: SubpixelLayout,
+    pub native_height: u32,
+    pub lighting: PanelLighting,
+}
+
+impl DisplayProfile {
+    pub fn matrix(&self) -> Mat3 {
+        [
+            [self.r, self.gr, self.br],
+            [self.rg, self.g, self.bg],
+            [self.rb, self.gb, self.b],
+        ]
+    }
+
+    // Takes the colour response from a measured panel, keeping the target gamma and layout of this profile
+    pub fn with_measurement(
+        &self,
+        m: &PanelMeasurement,
+        adapt_white: bool,
+    ) -> Result<DisplayProfile, String> {
+        if m.gamma.is_nan() || m.gamma <= 0.0 {
+            return Err("Gamma must be positive".to_string());
+        }
+        let (matrix, lum) = colorimetry::profile_matrix(m, adapt_white)?;
+        Ok(DisplayProfile {
+            r: matrix[0][0],
+            gr: matrix[0][1],
+            br: matrix[0][2],
+            rg: matrix[1][0],
+            g: matrix[1][1],
+            bg: matrix[1][2],
+            rb: matrix[2][0],
+            gb: matrix[2][1],
+            b: matrix[2][2],
+            lum,
+            gamma_offset: self.gamma * m.gamma / SOURCE_GAMMA - self.gamma,
+            ..*self
+        })
+    }
+
+    // What the simulated panel would measure, without any white point adaptation
+    pub fn measurement(&self) -> Result<PanelMeasurement, String> {
+        let gamma = SOURCE_GAMMA * (self.gamma + self.gamma_offset) / self.gamma;
+        colorimetry::measure_matrix(&self.matrix(), self.lum, gamma)
+    }
+}
+
+// Relative backlight luminance of the four DS Lite brightness levels
+const NDS_LITE_BRIGHTNESS: [f32; 4] = [0.25, 0.42, 0.66, 1.0];
+
+fn nds_lite_profile(level: usize) -> DisplayProfile {
+    DisplayProfile {
+        r: 0.93,
+        gr: 0.14,
+        br: -0.07,
+        rg: 0.025,
+        g: 0.90,
+        bg: 0.075,
+        rb: 0.008,
+        gb: -0.03,
+        b: 1.022,
+        lum: 0.935 * NDS_LITE_BRIGHTNESS[level],
+        gamma: 2.2,
+        gamma_offset: 0.0,
+        subpixel: SubpixelLayout::RGB,
+        native_height: 160,
+        lighting: PanelLighting::Backlit,
+    }
+}
+
+pub fn display_profile_preset(color_mode: u32) -> DisplayProfile {
+    match color_mode {
+        // GBA
+        1 => DisplayProfile {
+            r: 0.80,
+            gr: 0.275,
+            br: -0.075,
+            rg: 0.135,
+            g: 0.64,
+            bg: 0.225,
+            rb: 0.195,
+            gb: 0.155,
+            b: 0.65,
+            lum: 0.93,
+            gamma: 2.0,
+            gamma_offset: 0.5,
+            subpixel: SubpixelLayout::BGR,
+            native_height: 160,
+            lighting: PanelLighting::Reflective,
+        },
+        // GBA SP
+        2 => DisplayProfile {
+            r: 0.86,
+            gr: 0.10,
+            br: -0.06,
+            rg: 0.03,
+            g: 0.745,
+            bg: 0.0675,
+            rb: 0.0025,
+            gb: -0.03,
+            b: 1.0275,
+            lum: 0.97,
+            gamma: 2.0,
+            gamma_offset: 0.0,
+            subpixel: SubpixelLayout::RGB,
+            native_height: 160,
+            lighting: PanelLighting::FrontLit,
+        },
+        // GBA SP White
+        3 => DisplayProfile {
+            r: 0.955,
+            gr: 0.11,
+            br: -0.065,
+            rg: 0.0375,
+            g: 0.885,
+            bg: 0.0775,
+            rb: 0.0025,
+            gb: -0.03,
+            b: 1.0275,
+            lum: 0.94,
+            gamma: 2.0,
+            gamma_offset: 0.0,
+            subpixel: SubpixelLayout::RGB,
+            native_height: 160,
+            lighting: PanelLighting::FrontLit,
+        },
+        // GBA on NDS (Phat)
+        4 => DisplayProfile {
+            r: 0.705,
+            gr: 0.235,
+            br: 0.06,
+            rg: 0.09,
+            g: 0.585,
+            bg: 0.325,
+            rb: 0.1075,
+            gb: 0.1725,
+            b: 0.72,
+            lum: 0.905,
+            gamma: 2.2,
+            gamma_offset: 0.0,
+            subpixel: SubpixelLayout::RGB,
+            native_height: 160,
+            lighting: PanelLighting::Backlit,
+        },
+        // GBA on NDS Lite, brightest backlight level
+        5 => nds_lite_profile(3),
+        // GBA SP AGS-101 (backlit)
+        6 => DisplayProfile {
+            r: 0.96,
+            gr: 0.11,
+            br: -0.07,
+            rg: 0.0325,
+            g: 0.89,
+            bg: 0.0775,
+            rb: 0.001,
+            gb: -0.03,
+            b: 1.029,
+            lum: 0.935,
+            gamma: 2.2,
+            gamma_offset: 0.0,
+            subpixel: SubpixelLayout::RGB,
+            native_height: 160,
+            lighting: PanelLighting::Backlit,
+        },
+        // Game Boy Micro
+        7 => DisplayProfile {
+            r: 0.8025,
+            gr: 0.31,
+            br: -0.1125,
+            rg: 0.10,
+            g: 0.6875,
+            bg: 0.2125,
+            rb: 0.1225,
+            gb: 0.1125,
+            b: 0.765,
+            lum: 0.9,
+            gamma: 2.2,
+            gamma_offset: 0.0,
+            subpixel: SubpixelLayout::RGB,
+            native_height: 160,
+            lighting: PanelLighting::Backlit,
+        },
+        // sRGB passthrough, for the LCD grid only
+        8 => DisplayProfile {
+            r: 1.0,
+            gr: 0.0,
+            br: 0.0,
+            rg: 0.0,
+            g: 1.0,
+            bg: 0.0,
+            rb: 0.0,
+            gb: 0.0,
+            b: 1.0,
+            lum: 1.0,
+            gamma: 2.2,
+            gamma_offset: 0.0,
+            subpixel: SubpixelLayout::RGB,
+            native_height: 144,
+            lighting: PanelLighting::Backlit,
+        },
+        // GBA on NDS Lite, dimmer backlight levels 1 to 3
+        9..=11 => nds_lite_profile(color_mode as usize - 9),
+        // Pokefan531's GBC Display Profile
+        _ => DisplayProfile {
+            r: 0.80,
+            gr: 0.275,
+            br: -0.075,
+            rg: 0.135,
+            g: 0.64,
+            bg: 0.225,
+            rb: 0.195,
+            gb: 0.155,
+            b: 0.65,
+            lum: 0.93,
+            gamma: 2.2,
+            gamma_offset: -0.5,
+            subpixel: SubpixelLayout::RGB,
+            native_height: 144,
+            lighting: PanelLighting::Reflective,
+        },
+    }
+}
+
+pub enum LcdMode {
+    SameBoy,
+    Smear,
+    Nearest,
+    Lcd3x,
+    GbaGrid,
+    Delta,
+}
+
+impl LcdMode {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            0 => Self::SameBoy,
+            1 => Self::Smear,
+            3 => Self::Lcd3x,
+            4 => Self::GbaGrid,
+            5 => Self::Delta,
+            _ => Self::Nearest,
+        }
+    }
+}
+
+pub struct LcdParams {
+    pub mode: LcdMode,
+    // 1.0 is the default look of each renderer, 0.0 removes the grid
+    pub grid_strength: f32,
+}
+
+pub struct AmbientLight {
+    // 1.0 is a well lit room, lower values dim the screen and lose contrast
+    pub brightness: f32,
+    // Colour temperature of the light source in kelvin
+    pub temperature: f32,
+    // Strength of the reflection of the light source on the screen
+    pub glare: f32,
+    // Only used by front-lit panels
+    pub front_light: bool,
+}
+
+// Reflective panels never reach full black or full white
+const REFLECTIVE_BLACK: f32 = 0.05;
+const REFLECTIVE_WHITE: f32 = 0.85;
+
+// Unevenness of the front light, brightest along the bottom edge where the light enters
+#[inline(always)]
+fn front_light_pattern(x: f32, y: f32) -> f32 {
+    let distance = 1.0 - y;
+    let falloff = 1.0 - 0.35 * distance;
+    // Hot spots where the light sources sit along the edge
+    let hot_spots =
+        0.1 * (x * std::f32::consts::TAU * 3.0).cos().max(0.0) * (-distance * 10.0).exp();
+    // Light guide is dimmer towards the left and right ends
+    let sides = 1.0 - 0.15 * (2.0 * x - 1.0).powi(2);
+    (falloff + hot_spots) * sides
+}
+
+// Reflection of a light source above and to the left of the player
+#[inline(always)]
+fn glare_gradient(x: f32, y: f32) -> f32 {
+    let d = ((x * x + y * y) * 0.5).sqrt();
+    (1.0 - d).max(0.0).powi(3)
+}
+
+// Takes and returns linear colour, x and y are normalized screen coordinates
+fn ambient_lit(
+    c: Rgb<f32>,
+    lighting: PanelLighting,
+    ambient: &AmbientLight,
+    tint: Rgb<f32>,
+    x: f32,
+    y: f32,
+) -> Rgb<f32> {
+    let brightness = ambient.brightness.max(0.0);
+
+    // Dim light makes the panel lose contrast on top of getting darker
+    let dim = (1.0 - brightness).clamp(0.0, 1.0);
+    let contrast = 1.0 - 0.4 * dim;
+    let c = c.sub_f(0.18).mult_f(contrast).add_f(0.18).clamp01();
+    let reflectance = c
+        .mult_f(REFLECTIVE_WHITE - REFLECTIVE_BLACK)
+        .add_f(REFLECTIVE_BLACK);
+
+    let mut light = tint.mult_f(brightness);
+    let mut veil = 0.0;
+    if lighting == PanelLighting::FrontLit && ambient.front_light {
+        let pattern = front_light_pattern(x, y);
+        light = light.add(Rgb([0.92, 0.96, 1.0]).mult_f(pattern));
+        // Light scattered by the light guide washes out dark colours
+        veil = 0.04 * pattern;
+    }
+
+    reflectance
+        .mult(light)
+        .add_f(veil)
+        .add(tint.mult_f(brightness * ambient.glare * glare_gradient(x, y)))
+        .clamp01()
+}
+
+pub struct ViewingAngle {
+    // Degrees, positive looks down at the screen from above
+    pub vertical: f32,
+    // Degrees, either side looks the same
+    pub horizontal: f32,
+    // How much more the bottom of the screen is seen from above than the top, in degrees
+    pub tilt_gradient: f32,
+}
+
+// Angle at which each effect reaches full strength
+const WASH_OUT_ANGLE: f32 = 60.0;
+const HORIZONTAL_FALLOFF_ANGLE: f32 = 70.0;
+// Seen from below, grey levels start to invert past this angle
+const INVERSION_START: f32 = 10.0;
+const INVERSION_FULL: f32 = 45.0;
+const MAX_INVERSION: f32 = 0.8;
+// Blue light is retarded the most by the liquid crystal, so it shifts first
+const CHANNEL_RETARDATION: [f32; 3] = [0.9, 1.0, 1.15];
+
+// Takes and returns linear colour, y is the normalized screen coordinate.
+// Approximates the angular response of a twisted nematic panel.
+fn off_axis(c: Rgb<f32>, view: &ViewingAngle, y: f32) -> Rgb<f32> {
+    let vertical = view.vertical + view.tilt_gradient * (y - 0.5);
+    let above = (vertical / WASH_OUT_ANGLE).clamp(0.0, 1.0);
+    let below = (-vertical / WASH_OUT_ANGLE).clamp(0.0, 1.0);
+    let inversion =
+        ((-vertical - INVERSION_START) / (INVERSION_FULL - INVERSION_START)).clamp(0.0, 1.0);
+    let side = (view.horizontal.abs() / HORIZONTAL_FALLOFF_ANGLE).clamp(0.0, 1.0);
+
+    // Light leaking through dark pixels and dimming of bright ones
+    let black = 0.12 * above * above + 0.08 * side * side;
+    let white = 1.0 - 0.3 * side * side - 0.1 * below;
+
+    let c = c.clamp01();
+    let drive = c.to_gamma();
+    let mut out = Rgb([0.0; 3]);
+    for i in 0..3 {
+        let k = CHANNEL_RETARDATION[i];
+        // Washed out from above, darker mid tones from below
+        let gamma_ratio = (1.0 - 0.5 * (above * k).min(1.0)) * (1.0 + 0.8 * below * k);
+        let fold = (MAX_INVERSION * inversion * k).min(1.0);
+        // Bright levels fold back down, so gradients show a bright band that drops off
+        let t = c[i].powf(gamma_ratio) * (1.0 - fold * drive[i].powi(4));
+        out[i] = black + (white - black) * t;
+    }
+    out.clamp01()
+}
+
+pub enum LutStage {
+    // The LUT maps source colours directly, skipping the display profile
+    Replace,
+    // The LUT is applied to the output of the display profile
+    AfterProfile,
+}
+
+impl LutStage {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => Self::AfterProfile,
+            _ => Self::Replace,
+        }
+    }
+}
+
+pub struct ColorLut {
+    pub lut: Lut3d,
+    pub stage: LutStage,
+    pub interpolation: LutInterpolation,
+}
+
+// Swapping is its own inverse, so this converts both to and from the panel's order
+#[inline(always)]
+fn order_subpixels(c: Rgb<f32>, order: SubpixelOrder) -> Rgb<f32> {
+    match order {
+        SubpixelOrder::Rgb => c,
+        SubpixelOrder::Bgr => Rgb([c[2], c[1], c[0]]),
+    }
+}
+
+// Coverage of the lit part of each channel's subpixel over an output pixel, in source pixel units
+#[inline(always)]
+fn subpixel_fill_mask(pos: f32, footprint: f32, fill: f32) -> Rgb<f32> {
+    let x0 = pos - footprint * 0.5;
+    let x1 = pos + footprint * 0.5;
+    let gap = (1.0 - fill) / 6.0;
+    let mut mask = Rgb::<f32>([1.0, 1.0, 1.0]);
+    for c in 0..3 {
+        let mut dark = 0.0;
+        for pixel in (x0.floor() as i32)..=(x1.floor() as i32) {
+            let start = pixel as f32 + c as f32 / 3.0;
+            let end = start + 1.0 / 3.0;
+            for (a, b) in [(start, start + gap), (end - gap, end)] {
+                dark += (x1.min(b) - x0.max(a)).max(0.0);
+            }
+        }
+        mask[c] = 1.0 - dark / footprint;
+    }
+    mask
+}
+
+#[inline(always)]
+fn is_grid_gap(pos: f32, gap: f32) -> bool {
+    // Gap is the last output pixel of each source pixel, and only exists when upscaled at least 2x
+    gap <= 0.5 && pos.fract() >= 1.0 - gap
+}
+
+#[inline(always)]
+fn stripe_mask(subpixel: usize, strength: f32) -> Rgb<f32> {
+    let dim = (strength * 0.5).clamp(0.0, 1.0);
+    let mut mask = Rgb::<f32>([1.0 - dim, 1.0 - dim, 1.0 - dim]);
+    mask[subpixel] = 1.0;
+    // Compensate so that the mask does not change the average brightness
+    mask.div_f((3.0 - 2.0 * dim) / 3.0)
 }
 
 #[inline(always)]
@@ -58,7 +561,7 @@ fn int_smear(x: f32, dx: f32, d: f32, coeffs: &[f32; 7]) -> f32 {
 }
 
 #[inline(always)]
-fn color_correct(c: Rgb<f32>, p: &DisplayProfile) -> Rgb<f32> {
+pub fn color_correct(c: Rgb<f32>, p: &DisplayProfile) -> Rgb<f32> {
     let gamma = p.gamma + p.gamma_offset;
     let c = c.clamp01();
     let c = c.pow_f(gamma / p.gamma);
@@ -71,11 +574,171 @@ fn color_correct(c: Rgb<f32>, p: &DisplayProfile) -> Rgb<f32> {
     c.clamp01()
 }
 
-pub fn color_gb(img: &FloatImage, scale: u32, lcd_mode: u32, prof: &DisplayProfile) -> RgbaImage {
-    let (src_width, src_height) = (img.width(), img.height());
+// Strength of 0 shows the raw colours, 1 the fully corrected ones
+#[inline(always)]
+fn color_correct_blend(c: Rgb<f32>, p: &DisplayProfile, strength: f32) -> Rgb<f32> {
+    if strength >= 1.0 {
+        color_correct(c, p)
+    } else {
+        lerp_color(c.clamp01(), color_correct(c, p), strength.max(0.0))
+    }
+}
+
+// Slack for 8 bit quantization of the corrected screenshot, in linear space
+const INVERSE_TOLERANCE: f32 = 0.02;
+
+pub struct InverseCorrection {
+    pub image: FloatImage,
+    // Fraction of pixels with a channel clipped by the correction, whose raw colour is estimated
+    pub clipped: f32,
+    // Fraction of pixels that could not have been produced by the profile
+    pub out_of_range: f32,
+}
+
+// The true value of a clipped channel is unknown, so slide along it until the raw colour
+// reaches the edge of the valid range. Clipping usually comes from a raw channel sitting at 0.
+#[inline(always)]
+fn unclip_channel(raw: Rgb<f32>, inv: &Mat3, channel: usize, direction: f32, lum: f32) -> Rgb<f32> {
+    let mut step = f32::MAX;
+    for i in 0..3 {
+        let d = inv[i][channel] * direction;
+        if d > 0.0 && raw[i] < lum {
+            step = step.min((lum - raw[i]) / d);
+        } else if d < 0.0 && raw[i] > 0.0 {
+            step = step.min(raw[i] / -d);
+        }
+    }
+    if step == f32::MAX {
+        return raw;
+    }
+    let t = step * direction;
+    Rgb([
+        raw[0] + t * inv[0][channel],
+        raw[1] + t * inv[1][channel],
+        raw[2] + t * inv[2][channel],
+    ])
+}
 
-    let width = src_width * scale;
-    let height = src_height * scale;
+// Estimates the raw game colours of a screenshot that had the profile's colour correction applied
+pub fn invert_color_correction(
+    img: &FloatImage,
+    prof: &DisplayProfile,
+) -> Result<InverseCorrection, String> {
+    let inv = mat3_inverse(&prof.matrix()).ok_or("Colour profile matrix is not invertible")?;
+    let gamma = prof.gamma + prof.gamma_offset;
+
+    let mut clipped_count = 0u64;
+    let mut out_of_range_count = 0u64;
+    let image = FloatImage::from_fn(img.width(), img.height(), |x, y| {
+        let corrected = img.get_pixel(x, y).clamp01();
+        let mut c = mat3_mul_color(&inv, corrected);
+
+        let mut clipped = false;
+        for channel in 0..3 {
+            if corrected[channel] <= 0.0 {
+                c = unclip_channel(c, &inv, channel, -1.0, prof.lum);
+                clipped = true;
+            } else if corrected[channel] >= 1.0 {
+                c = unclip_channel(c, &inv, channel, 1.0, prof.lum);
+                clipped = true;
+            }
+        }
+        if clipped {
+            clipped_count += 1;
+        }
+
+        let in_range =
+            (0..3).all(|i| c[i] >= -INVERSE_TOLERANCE && c[i] <= prof.lum + INVERSE_TOLERANCE);
+        if !in_range {
+            out_of_range_count += 1;
+        }
+        // Colours outside of the profile's gamut are clamped to the closest reachable value
+        let c = c.clamp(Rgb([0.0; 3]), Rgb([prof.lum; 3]));
+        c.div_f(prof.lum).pow_f(prof.gamma / gamma)
+    });
+
+    let pixel_count = (img.width() as u64 * img.height() as u64).max(1) as f32;
+    Ok(InverseCorrection {
+        image,
+        clipped: clipped_count as f32 / pixel_count,
+        out_of_range: out_of_range_count as f32 / pixel_count,
+    })
+}
+
+// Output size does not have to be an integer multiple of the source, as the shaders work in texture space
+#[allow(clippy::too_many_arguments)]
+pub fn color_gb(
+    img: &FloatImage,
+    width: u32,
+    height: u32,
+    lcd: &LcdParams,
+    prof: &DisplayProfile,
+    correction_strength: f32,
+    color_lut: Option<&ColorLut>,
+    ambient: Option<&AmbientLight>,
+    viewing: Option<&ViewingAngle>,
+) -> RgbaImage {
+    match prof.subpixel.orientation {
+        SubpixelOrientation::Vertical => color_lcd(
+            img,
+            width,
+            height,
+            lcd,
+            prof,
+            correction_strength,
+            color_lut,
+            ambient,
+            viewing,
+            false,
+        ),
+        // Horizontal stripes are rendered as vertical ones on the transposed image
+        SubpixelOrientation::Horizontal => {
+            let transposed =
+                FloatImage::from_fn(img.height(), img.width(), |x, y| *img.get_pixel(y, x));
+            let out = color_lcd(
+                &transposed,
+                height,
+                width,
+                lcd,
+                prof,
+                correction_strength,
+                color_lut,
+                ambient,
+                viewing,
+                true,
+            );
+            RgbaImage::from_fn(width, height, |x, y| *out.get_pixel(y, x))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn color_lcd(
+    img: &FloatImage,
+    width: u32,
+    height: u32,
+    lcd: &LcdParams,
+    prof: &DisplayProfile,
+    correction_strength: f32,
+    color_lut: Option<&ColorLut>,
+    ambient: Option<&AmbientLight>,
+    viewing: Option<&ViewingAngle>,
+    transposed: bool,
+) -> RgbaImage {
+    // Renderers lay out subpixels in RGB order, so BGR panels swap the channels around them
+    let order = prof.subpixel.order;
+    let fill = prof.subpixel.fill.clamp(0.0, 1.0);
+    let reordered;
+    let img = if order == SubpixelOrder::Bgr {
+        reordered = FloatImage::from_fn(img.width(), img.height(), |x, y| {
+            order_subpixels(*img.get_pixel(x, y), order)
+        });
+        &reordered
+    } else {
+        img
+    };
+
+    let (src_width, src_height) = (img.width(), img.height());
 
     let src_width_f = src_width as f32;
     let src_height_f = src_height as f32;
@@ -117,9 +780,20 @@ pub fn color_gb(img: &FloatImage, scale: u32, lcd_mode: u32, prof: &DisplayProfi
         1.0 / 13.0,
     ];
 
-    let color_low = 0.8;
+    // Grid strength of 1 matches the original SameBoy shader
+    let grid_strength = lcd.grid_strength.max(0.0);
+    let color_low = (1.0 - 0.2 * grid_strength).max(0.0);
     let color_high = 1.0;
-    let scanline_depth = 0.1;
+    let scanline_depth = (0.1 * grid_strength).min(1.0);
+
+    // Size of one output pixel in source pixels
+    let gap_x = out_texel_size_x * src_width_f;
+    let gap_y = out_texel_size_y * src_height_f;
+
+    let ambient_tint = match ambient {
+        Some(a) => color_temperature_rgb(a.temperature),
+        None => Rgb([1.0, 1.0, 1.0]),
+    };
 
     let mut out = RgbaImage::new(width as u32, height as u32);
 
@@ -128,217 +802,304 @@ pub fn color_gb(img: &FloatImage, scale: u32, lcd_mode: u32, prof: &DisplayProfi
         for x in 0..width {
             let tex_coord_x = out_texel_size_x * (x as f32 + 0.5);
 
-            let color = if lcd_mode == 1 {
-                let tli_x = (tex_coord_x * src_width_f - 0.4999) as i32;
-                let tli_y = (tex_coord_y * src_height_f - 0.4999) as i32;
-
-                let subpix = (tex_coord_x * src_width_f - 0.4999 - tli_x as f32) * 3.0;
-                let rsubpix = out_texel_size_x * src_width_f * 3.0;
-
-                let mut lcol = Rgb::<f32>([
-                    int_smear(subpix + 1.0, rsubpix, 1.5, &coeffs_x),
-                    int_smear(subpix, rsubpix, 1.5, &coeffs_x),
-                    int_smear(subpix - 1.0, rsubpix, 1.5, &coeffs_x),
-                ]);
-                let mut rcol = Rgb::<f32>([
-                    int_smear(subpix - 2.0, rsubpix, 1.5, &coeffs_x),
-                    int_smear(subpix - 3.0, rsubpix, 1.5, &coeffs_x),
-                    int_smear(subpix - 4.0, rsubpix, 1.5, &coeffs_x),
-                ]);
-
-                if prof.bgr {
-                    let r = lcol[0];
-                    let g = lcol[1];
-                    let b = lcol[2];
-                    lcol[0] = b;
-                    lcol[1] = g;
-                    lcol[2] = r;
-                    let r = rcol[0];
-                    let g = rcol[1];
-                    let b = rcol[2];
-                    rcol[0] = b;
-                    rcol[1] = g;
-                    rcol[2] = r;
-                }
+            let color = match lcd.mode {
+                LcdMode::Smear => {
+                    let tli_x = (tex_coord_x * src_width_f - 0.4999) as i32;
+                    let tli_y = (tex_coord_y * src_height_f - 0.4999) as i32;
 
-                let subpix = tex_coord_y * src_height_f - 0.4999 - tli_y as f32;
-                let rsubpix = out_texel_size_y * src_height_f;
-                let tcol = int_smear(subpix, rsubpix, 0.63, &coeffs_y);
-                let bcol = int_smear(subpix - 1.0, rsubpix, 0.63, &coeffs_y);
-
-                let top_left_color = load_img(tli_x, tli_y).mult(lcol).mult_f(tcol);
-                let top_right_color = load_img(tli_x + 1, tli_y).mult(rcol).mult_f(tcol);
-                let bottom_left_color = load_img(tli_x, tli_y + 1).mult(lcol).mult_f(bcol);
-                let bottom_right_color = load_img(tli_x + 1, tli_y + 1).mult(rcol).mult_f(bcol);
-
-                top_left_color
-                    .add(bottom_right_color)
-                    .add(bottom_left_color)
-                    .add(top_right_color)
-            } else if lcd_mode == 0 {
-                /*
-                    Expat License
-
-                    Copyright (c) 2015-2024 Lior Halphon
-
-                    Permission is hereby granted, free of charge, to any person obtaining a copy
-                    of this software and associated documentation files (the "Software"), to deal
-                    in the Software without restriction, including without limitation the rights
-                    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
-                    copies of the Software, and to permit persons to whom the Software is
-                    furnished to do so, subject to the following conditions:
-
-                    The above copyright notice and this permission notice shall be included in all
-                    copies or substantial portions of the Software.
-
-                    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
-                    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
-                    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
-                    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
-                    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
-                    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
-                    SOFTWARE.
-                */
-                let sub_pos_x = (tex_coord_x * src_width_f).fract() * 6.0;
-                let sub_pos_y = (tex_coord_y * src_height_f).fract() * 6.0;
-
-                let mut left = sample_img(tex_coord_x - out_texel_size_x, tex_coord_y);
-                let mut center = sample_img(tex_coord_x, tex_coord_y);
-                let mut right = sample_img(tex_coord_x + out_texel_size_x, tex_coord_y);
-
-                if sub_pos_y < 1.0 {
-                    let left_n = sample_img(
-                        tex_coord_x - out_texel_size_x,
-                        tex_coord_y - out_texel_size_y,
-                    );
-                    left = lerp_color(left, left_n, 0.5 - sub_pos_y * 0.5);
-                    left = left.mult_f(sub_pos_y * scanline_depth + (1.0 - scanline_depth));
-
-                    let top_n = sample_img(tex_coord_x, tex_coord_y - out_texel_size_y);
-                    center = lerp_color(center, top_n, 0.5 - sub_pos_y * 0.5);
-                    center = center.mult_f(sub_pos_y * scanline_depth + (1.0 - scanline_depth));
-
-                    let right_n = sample_img(
-                        tex_coord_x + out_texel_size_x,
-                        tex_coord_y - out_texel_size_y,
-                    );
-                    right = lerp_color(right, right_n, 0.5 - sub_pos_y * 0.5);
-                    right = right.mult_f(sub_pos_y * scanline_depth + (1.0 - scanline_depth));
-                } else if sub_pos_y > 5.0 {
-                    let left_n = sample_img(
-                        tex_coord_x - out_texel_size_x,
-                        tex_coord_y + out_texel_size_y,
-                    );
-                    left = lerp_color(left, left_n, (sub_pos_y - 5.0) * 0.5);
-                    left = left.mult_f((6.0 - sub_pos_y) * scanline_depth + (1.0 - scanline_depth));
-
-                    let bottom_n = sample_img(tex_coord_x, tex_coord_y + out_texel_size_y);
-                    center = lerp_color(center, bottom_n, (sub_pos_y - 5.0) * 0.5);
-                    center =
-                        center.mult_f((6.0 - sub_pos_y) * scanline_depth + (1.0 - scanline_depth));
-
-                    let right_n = sample_img(
-                        tex_coord_x + out_texel_size_x,
-                        tex_coord_y + out_texel_size_y,
-                    );
-                    right = lerp_color(right, right_n, (sub_pos_y - 5.0) * 0.5);
-                    right =
-                        right.mult_f((6.0 - sub_pos_y) * scanline_depth + (1.0 - scanline_depth));
-                }
+                    let subpix = (tex_coord_x * src_width_f - 0.4999 - tli_x as f32) * 3.0;
+                    let rsubpix = out_texel_size_x * src_width_f * 3.0;
 
-                let mid_left = lerp_color(left, center, 0.5);
-                let mid_right = lerp_color(right, center, 0.5);
+                    let lcol = Rgb::<f32>([
+                        int_smear(subpix + 1.0, rsubpix, 1.5, &coeffs_x),
+                        int_smear(subpix, rsubpix, 1.5, &coeffs_x),
+                        int_smear(subpix - 1.0, rsubpix, 1.5, &coeffs_x),
+                    ]);
+                    let rcol = Rgb::<f32>([
+                        int_smear(subpix - 2.0, rsubpix, 1.5, &coeffs_x),
+                        int_smear(subpix - 3.0, rsubpix, 1.5, &coeffs_x),
+                        int_smear(subpix - 4.0, rsubpix, 1.5, &coeffs_x),
+                    ]);
 
-                if sub_pos_x < 1.0 {
-                    lerp_color(
-                        Rgb::<f32>([
-                            color_high * center[0],
-                            color_low * center[1],
-                            color_high * left[2],
-                        ]),
-                        Rgb::<f32>([
-                            color_high * center[0],
-                            color_low * center[1],
-                            color_low * left[2],
-                        ]),
-                        sub_pos_x,
-                    )
-                } else if sub_pos_x < 2.0 {
-                    lerp_color(
-                        Rgb::<f32>([
-                            color_high * center[0],
-                            color_low * center[1],
-                            color_low * left[2],
-                        ]),
-                        Rgb::<f32>([
-                            color_high * center[0],
-                            color_high * center[1],
-                            color_low * mid_left[2],
-                        ]),
-                        sub_pos_x - 1.0,
-                    )
-                } else if sub_pos_x < 3.0 {
-                    lerp_color(
-                        Rgb::<f32>([
-                            color_high * center[0],
-                            color_high * center[1],
-                            color_low * mid_left[2],
-                        ]),
-                        Rgb::<f32>([
-                            color_low * mid_right[0],
-                            color_high * center[1],
-                            color_low * center[2],
-                        ]),
-                        sub_pos_x - 2.0,
-                    )
-                } else if sub_pos_x < 4.0 {
-                    lerp_color(
-                        Rgb::<f32>([
-                            color_low * mid_right[0],
-                            color_high * center[1],
-                            color_low * center[2],
-                        ]),
-                        Rgb::<f32>([
-                            color_low * right[0],
-                            color_high * center[1],
-                            color_high * center[2],
-                        ]),
-                        sub_pos_x - 3.0,
-                    )
-                } else if sub_pos_x < 5.0 {
-                    lerp_color(
-                        Rgb::<f32>([
-                            color_low * right[0],
-                            color_high * center[1],
-                            color_high * center[2],
-                        ]),
-                        Rgb::<f32>([
-                            color_low * right[0],
-                            color_low * mid_right[1],
-                            color_high * center[2],
-                        ]),
-                        sub_pos_x - 4.0,
-                    )
-                } else {
+                    let subpix = tex_coord_y * src_height_f - 0.4999 - tli_y as f32;
+                    let rsubpix = out_texel_size_y * src_height_f;
+                    let tcol = int_smear(subpix, rsubpix, 0.63, &coeffs_y);
+                    let bcol = int_smear(subpix - 1.0, rsubpix, 0.63, &coeffs_y);
+
+                    let top_left_color = load_img(tli_x, tli_y).mult(lcol).mult_f(tcol);
+                    let top_right_color = load_img(tli_x + 1, tli_y).mult(rcol).mult_f(tcol);
+                    let bottom_left_color = load_img(tli_x, tli_y + 1).mult(lcol).mult_f(bcol);
+                    let bottom_right_color = load_img(tli_x + 1, tli_y + 1).mult(rcol).mult_f(bcol);
+
+                    let smeared = top_left_color
+                        .add(bottom_right_color)
+                        .add(bottom_left_color)
+                        .add(top_right_color);
+
+                    // Fade between flat pixels and the full smear
                     lerp_color(
-                        Rgb::<f32>([
-                            color_low * right[0],
-                            color_low * mid_right[1],
-                            color_high * center[2],
-                        ]),
-                        Rgb::<f32>([
-                            color_high * right[0],
-                            color_low * right[1],
-                            color_high * center[2],
-                        ]),
-                        sub_pos_x - 5.0,
+                        sample_img(tex_coord_x, tex_coord_y),
+                        smeared,
+                        grid_strength.min(1.0),
                     )
                 }
-            } else {
-                sample_img(tex_coord_x, tex_coord_y)
+                LcdMode::SameBoy => {
+                    /*
+                        Expat License
+
+                        Copyright (c) 2015-2024 Lior Halphon
+
+                        Permission is hereby granted, free of charge, to any person obtaining a copy
+                        of this software and associated documentation files (the "Software"), to deal
+                        in the Software without restriction, including without limitation the rights
+                        to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+                        copies of the Software, and to permit persons to whom the Software is
+                        furnished to do so, subject to the following conditions:
+
+                        The above copyright notice and this permission notice shall be included in all
+                        copies or substantial portions of the Software.
+
+                        THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+                        IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+                        FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+                        AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+                        LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+                        OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+                        SOFTWARE.
+                    */
+                    let sub_pos_x = (tex_coord_x * src_width_f).fract() * 6.0;
+                    let sub_pos_y = (tex_coord_y * src_height_f).fract() * 6.0;
+
+                    let mut left = sample_img(tex_coord_x - out_texel_size_x, tex_coord_y);
+                    let mut center = sample_img(tex_coord_x, tex_coord_y);
+                    let mut right = sample_img(tex_coord_x + out_texel_size_x, tex_coord_y);
+
+                    if sub_pos_y < 1.0 {
+                        let left_n = sample_img(
+                            tex_coord_x - out_texel_size_x,
+                            tex_coord_y - out_texel_size_y,
+                        );
+                        left = lerp_color(left, left_n, 0.5 - sub_pos_y * 0.5);
+                        left = left.mult_f(sub_pos_y * scanline_depth + (1.0 - scanline_depth));
+
+                        let top_n = sample_img(tex_coord_x, tex_coord_y - out_texel_size_y);
+                        center = lerp_color(center, top_n, 0.5 - sub_pos_y * 0.5);
+                        center = center.mult_f(sub_pos_y * scanline_depth + (1.0 - scanline_depth));
+
+                        let right_n = sample_img(
+                            tex_coord_x + out_texel_size_x,
+                            tex_coord_y - out_texel_size_y,
+                        );
+                        right = lerp_color(right, right_n, 0.5 - sub_pos_y * 0.5);
+                        right = right.mult_f(sub_pos_y * scanline_depth + (1.0 - scanline_depth));
+                    } else if sub_pos_y > 5.0 {
+                        let left_n = sample_img(
+                            tex_coord_x - out_texel_size_x,
+                            tex_coord_y + out_texel_size_y,
+                        );
+                        left = lerp_color(left, left_n, (sub_pos_y - 5.0) * 0.5);
+                        left = left
+                            .mult_f((6.0 - sub_pos_y) * scanline_depth + (1.0 - scanline_depth));
+
+                        let bottom_n = sample_img(tex_coord_x, tex_coord_y + out_texel_size_y);
+                        center = lerp_color(center, bottom_n, (sub_pos_y - 5.0) * 0.5);
+                        center = center
+                            .mult_f((6.0 - sub_pos_y) * scanline_depth + (1.0 - scanline_depth));
+
+                        let right_n = sample_img(
+                            tex_coord_x + out_texel_size_x,
+                            tex_coord_y + out_texel_size_y,
+                        );
+                        right = lerp_color(right, right_n, (sub_pos_y - 5.0) * 0.5);
+                        right = right
+                            .mult_f((6.0 - sub_pos_y) * scanline_depth + (1.0 - scanline_depth));
+                    }
+
+                    let mid_left = lerp_color(left, center, 0.5);
+                    let mid_right = lerp_color(right, center, 0.5);
+
+                    if sub_pos_x < 1.0 {
+                        lerp_color(
+                            Rgb::<f32>([
+                                color_high * center[0],
+                                color_low * center[1],
+                                color_high * left[2],
+                            ]),
+                            Rgb::<f32>([
+                                color_high * center[0],
+                                color_low * center[1],
+                                color_low * left[2],
+                            ]),
+                            sub_pos_x,
+                        )
+                    } else if sub_pos_x < 2.0 {
+                        lerp_color(
+                            Rgb::<f32>([
+                                color_high * center[0],
+                                color_low * center[1],
+                                color_low * left[2],
+                            ]),
+                            Rgb::<f32>([
+                                color_high * center[0],
+                                color_high * center[1],
+                                color_low * mid_left[2],
+                            ]),
+                            sub_pos_x - 1.0,
+                        )
+                    } else if sub_pos_x < 3.0 {
+                        lerp_color(
+                            Rgb::<f32>([
+                                color_high * center[0],
+                                color_high * center[1],
+                                color_low * mid_left[2],
+                            ]),
+                            Rgb::<f32>([
+                                color_low * mid_right[0],
+                                color_high * center[1],
+                                color_low * center[2],
+                            ]),
+                            sub_pos_x - 2.0,
+                        )
+                    } else if sub_pos_x < 4.0 {
+                        lerp_color(
+                            Rgb::<f32>([
+                                color_low * mid_right[0],
+                                color_high * center[1],
+                                color_low * center[2],
+                            ]),
+                            Rgb::<f32>([
+                                color_low * right[0],
+                                color_high * center[1],
+                                color_high * center[2],
+                            ]),
+                            sub_pos_x - 3.0,
+                        )
+                    } else if sub_pos_x < 5.0 {
+                        lerp_color(
+                            Rgb::<f32>([
+                                color_low * right[0],
+                                color_high * center[1],
+                                color_high * center[2],
+                            ]),
+                            Rgb::<f32>([
+                                color_low * right[0],
+                                color_low * mid_right[1],
+                                color_high * center[2],
+                            ]),
+                            sub_pos_x - 4.0,
+                        )
+                    } else {
+                        lerp_color(
+                            Rgb::<f32>([
+                                color_low * right[0],
+                                color_low * mid_right[1],
+                                color_high * center[2],
+                            ]),
+                            Rgb::<f32>([
+                                color_high * right[0],
+                                color_low * right[1],
+                                color_high * center[2],
+                            ]),
+                            sub_pos_x - 5.0,
+                        )
+                    }
+                }
+                LcdMode::Lcd3x => {
+                    // Each pixel is split into R, G and B columns, with dark lines between pixels
+                    let pos_x = tex_coord_x * src_width_f;
+                    let pos_y = tex_coord_y * src_height_f;
+                    let subpixel = ((pos_x.fract() * 3.0) as usize).min(2);
+                    let color = sample_img(tex_coord_x, tex_coord_y)
+                        .mult(stripe_mask(subpixel, grid_strength));
+                    if is_grid_gap(pos_x, gap_x) || is_grid_gap(pos_y, gap_y) {
+                        color.mult_f((1.0 - 0.6 * grid_strength).max(0.0))
+                    } else {
+                        color
+                    }
+                }
+                LcdMode::GbaGrid => {
+                    // Gaps between pixels stand out more the brighter the pixel is
+                    let pos_x = tex_coord_x * src_width_f;
+                    let pos_y = tex_coord_y * src_height_f;
+                    let color = sample_img(tex_coord_x, tex_coord_y);
+                    if is_grid_gap(pos_x, gap_x) || is_grid_gap(pos_y, gap_y) {
+                        let luminance = order_subpixels(color, order).luminance();
+                        let darkness = grid_strength * (0.15 + 0.45 * luminance);
+                        color.mult_f((1.0 - darkness).max(0.0))
+                    } else {
+                        color
+                    }
+                }
+                LcdMode::Delta => {
+                    // Subpixel triads of every other row are offset by half a pixel
+                    let pos_y = tex_coord_y * src_height_f;
+                    let row = pos_y.floor() as i32;
+                    let shift = if row % 2 == 1 { 0.5 } else { 0.0 };
+                    let pos_x = tex_coord_x * src_width_f - shift;
+                    let column = pos_x.floor() as i32;
+                    let subpixel = (((pos_x - column as f32) * 3.0) as usize).min(2);
+                    let color = load_img(column, row).mult(stripe_mask(subpixel, grid_strength));
+                    if is_grid_gap(pos_y, gap_y) {
+                        color.mult_f((1.0 - 0.4 * grid_strength).max(0.0))
+                    } else {
+                        color
+                    }
+                }
+                LcdMode::Nearest => sample_img(tex_coord_x, tex_coord_y),
             };
 
-            let color = color_correct(color, &prof).to_gamma();
+            // Gaps between subpixels, along the stripes
+            let color = match lcd.mode {
+                LcdMode::Nearest => color,
+                _ if fill >= 1.0 => color,
+                _ => {
+                    let pos_x = tex_coord_x * src_width_f;
+                    let pos_x = match lcd.mode {
+                        LcdMode::Delta if (tex_coord_y * src_height_f) as i32 % 2 == 1 => {
+                            pos_x - 0.5
+                        }
+                        _ => pos_x,
+                    };
+                    color.mult(subpixel_fill_mask(pos_x, gap_x, fill))
+                }
+            };
+            let color = order_subpixels(color, order);
+
+            // LUTs are applied in gamma space, which is what .cube and Hald files are authored for
+            let color = match color_lut {
+                None => color_correct_blend(color, prof, correction_strength).to_gamma(),
+                Some(c) => match c.stage {
+                    LutStage::Replace => c.lut.apply(color.clamp01().to_gamma(), c.interpolation),
+                    LutStage::AfterProfile => c.lut.apply(
+                        color_correct_blend(color, prof, correction_strength).to_gamma(),
+                        c.interpolation,
+                    ),
+                }
+                .clamp01(),
+            };
+
+            let (screen_x, screen_y) = if transposed {
+                (tex_coord_y, tex_coord_x)
+            } else {
+                (tex_coord_x, tex_coord_y)
+            };
+            // The angle changes what the panel lets through, before it is lit
+            let color = match viewing {
+                Some(v) => off_axis(color.to_linear(), v, screen_y).to_gamma(),
+                None => color,
+            };
+            let color = match ambient {
+                Some(a) if prof.lighting != PanelLighting::Backlit => ambient_lit(
+                    color.to_linear(),
+                    prof.lighting,
+                    a,
+                    ambient_tint,
+                    screen_x,
+                    screen_y,
+                )
+                .to_gamma(),
+                _ => color,
+            };
 
             unsafe {
                 out.unsafe_put_pixel(
@@ -0,0 +1,245 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{gb, shader_support};
+
+use gb::ShadeImage;
+use image::{Rgb, Rgba, RgbaImage};
+use shader_support::*;
+
+// Colours are RGB555, lightest shade first
+#[derive(Clone, Copy)]
+pub struct BootPalette {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+// The colours stored in the boot ROM, four per palette
+#[rustfmt::skip]
+const COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// OBJ0, OBJ1 and BG of each combination, as the index of their first colour.
+// Most start on a palette, a few start part way into one, as in the ROM.
+#[rustfmt::skip]
+const COMBINATIONS: [[u8; 3]; 51] = [
+    [16, 16, 116], [72, 72, 72], [80, 80, 80], [96, 96, 96], [36, 36, 36],
+    [0, 0, 0], [108, 108, 108], [20, 20, 20], [48, 48, 48], [104, 104, 104],
+    [64, 32, 32], [16, 112, 112], [16, 8, 8], [12, 16, 16], [16, 116, 116],
+    [112, 16, 112], [8, 68, 8], [64, 64, 32], [16, 16, 28], [16, 16, 72],
+    [16, 16, 80], [76, 76, 36], [15, 15, 44], [68, 68, 8], [16, 16, 8],
+    [16, 16, 12], [112, 112, 0], [12, 12, 0], [0, 0, 4], [72, 88, 72],
+    [80, 88, 80], [96, 88, 96], [64, 88, 32], [68, 16, 52], [111, 0, 56],
+    [111, 16, 60], [76, 91, 36], [64, 112, 40], [16, 92, 112], [68, 88, 8],
+    [16, 0, 8], [16, 112, 12], [112, 12, 0], [12, 112, 16], [84, 112, 16],
+    [12, 112, 0], [100, 12, 112], [0, 112, 32], [16, 12, 112], [112, 12, 24],
+    [16, 112, 116],
+];
+
+// Combination picked by holding a button combination while the logo is shown.
+// Up, Up + A, Up + B, Left, Left + A, Left + B, Down, Down + A, Down + B,
+// Right, Right + A and Right + B.
+pub const BUTTON_COMBINATIONS: [usize; 12] = [5, 43, 28, 48, 40, 7, 8, 3, 49, 1, 0, 6];
+
+// Games without an entry of their own get the same palette as Right + A
+pub const DEFAULT_COMBINATION: usize = 0;
+
+// Title checksums the boot ROM knows, and the combination for each.
+// From FIRST_DUPLICATE on, checksums are shared and the fourth letter of the title decides.
+#[rustfmt::skip]
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+#[rustfmt::skip]
+const TITLE_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17,
+    46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+const FIRST_DUPLICATE: usize = 65;
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+impl BootPalette {
+    pub fn from_combination(index: usize) -> Option<Self> {
+        let [obj0, obj1, bg] = COMBINATIONS.get(index)?.map(|start| {
+            let start = start as usize;
+            [0, 1, 2, 3].map(|i| COLORS[start + i])
+        });
+        Some(BootPalette { bg, obj0, obj1 })
+    }
+}
+
+// The boot ROM sums the 16 title bytes of the cartridge header
+pub fn title_checksum(title: &str) -> u8 {
+    title
+        .bytes()
+        .map(|b| b.to_ascii_uppercase())
+        .chain(std::iter::repeat(0))
+        .take(16)
+        .fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+// Only games licensed by Nintendo are looked up, the rest always get the default palette
+pub fn combination_for_title(title: &str, nintendo_licensed: bool) -> usize {
+    if !nintendo_licensed {
+        return DEFAULT_COMBINATION;
+    }
+    let checksum = title_checksum(title);
+    let fourth = title
+        .as_bytes()
+        .get(3)
+        .copied()
+        .unwrap_or(0)
+        .to_ascii_uppercase();
+    (0..TITLE_CHECKSUMS.len())
+        .find(|&i| {
+            TITLE_CHECKSUMS[i] == checksum
+                && (i < FIRST_DUPLICATE || FOURTH_LETTERS[i - FIRST_DUPLICATE] == fourth)
+        })
+        .map(|i| TITLE_COMBINATIONS[i] as usize)
+        .unwrap_or(DEFAULT_COMBINATION)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Layer {
+    Background,
+    Object0,
+    Object1,
+}
+
+// Layers are painted onto a mask: red marks OBJ0, blue marks OBJ1, anything else is background
+pub fn layers_from_mask(mask: &RgbaImage) -> Vec<Layer> {
+    mask.pixels()
+        .map(|p| {
+            if p[3] < 128 {
+                Layer::Background
+            } else if p[0] >= 128 && p[2] < 128 {
+                Layer::Object0
+            } else if p[2] >= 128 && p[0] < 128 {
+                Layer::Object1
+            } else {
+                Layer::Background
+            }
+        })
+        .collect()
+}
+
+// Each 5 bit channel is expanded to 8 bits before the colour correction
+fn palette_color(rgb555: u16) -> Rgb<f32> {
+    let channel = |shift: u16| {
+        let v = ((rgb555 >> shift) & 0x1F) as u8;
+        (v << 3) | (v >> 2)
+    };
+    rgba_u8_to_rgb_f32(Rgba([channel(0), channel(5), channel(10), 255])).to_linear()
+}
+
+// Raw colours in linear light, ready for the GBC colour correction
+pub fn colorize(
+    shades: &ShadeImage,
+    layers: Option<&[Layer]>,
+    palette: &BootPalette,
+) -> FloatImage {
+    let colors = |p: &[u16; 4]| p.map(palette_color);
+    let (bg, obj0, obj1) = (
+        colors(&palette.bg),
+        colors(&palette.obj0),
+        colors(&palette.obj1),
+    );
+    FloatImage::from_fn(shades.width(), shades.height(), |x, y| {
+        let shade = shades.get_pixel(x, y)[0] as usize;
+        let layer = layers
+            .map(|l| l[(y * shades.width() + x) as usize])
+            .unwrap_or(Layer::Background);
+        match layer {
+            Layer::Background => bg[shade],
+            Layer::Object0 => obj0[shade],
+            Layer::Object1 => obj1[shade],
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn title_lookup_uses_checksum() {
+        assert_eq!(title_checksum("POKEMON RED"), 0x14);
+        let combination = combination_for_title("POKEMON RED", true);
+        let palette = BootPalette::from_combination(combination).unwrap();
+        assert_eq!(palette.bg, [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+        assert_eq!(palette.obj0, [0x7FFF, 0x1BEF, 0x0200, 0x0000]);
+    }
+
+    #[test]
+    fn title_lookup_breaks_ties_on_fourth_letter() {
+        assert_eq!(title_checksum("POKEMON BLUE"), 0x61);
+        assert_eq!(title_checksum("VEGAS STAKES"), 0x61);
+        assert_eq!(combination_for_title("POKEMON BLUE", true), 11);
+        assert_eq!(combination_for_title("VEGAS STAKES", true), 41);
+    }
+
+    #[test]
+    fn unlicensed_games_get_the_default() {
+        assert_eq!(
+            combination_for_title("POKEMON RED", false),
+            DEFAULT_COMBINATION
+        );
+        assert_eq!(BUTTON_COMBINATIONS[10], DEFAULT_COMBINATION);
+    }
+}
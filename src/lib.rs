@@ -17,16 +17,29 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+mod color_adjust;
+mod colorimetry;
 mod crt;
 mod gb;
+mod gb_camera_save;
+mod gb_on_gba;
+mod gb_palette;
+mod gb_printer;
+mod gb_tiles;
 mod gbc;
+mod gbc_colorize;
+mod lut;
+mod profile_fit;
+mod quantize;
 mod scaling;
 mod shader_support;
 mod utils;
+mod wear;
 
 use base64::{engine::general_purpose, Engine as _};
 use exif::*;
 use scaling::*;
+use shader_support::{float_to_byte, ShaderSupport};
 use std::io::Cursor;
 use utils::set_panic_hook;
 use wasm_bindgen::prelude::*;
@@ -36,9 +49,9 @@ extern "C" {
     fn alert(s: &str);
 }
 
-fn parse_exif_orientation(data: &Vec<u8>) -> u32 {
+fn parse_exif_orientation(data: &[u8]) -> u32 {
     let exifreader = exif::Reader::new();
-    let exif_result = exifreader.read_from_container(&mut Cursor::new(&data));
+    let exif_result = exifreader.read_from_container(&mut Cursor::new(data));
     if exif_result.is_err() {
         return 1;
     }
@@ -54,24 +67,7 @@ fn parse_exif_orientation(data: &Vec<u8>) -> u32 {
     }
 }
 
-#[wasm_bindgen(js_name = processImageGb)]
-pub fn process_image_gb(
-    mode: i32,
-    dither: bool,
-    brightness: f32,
-    contrast: f32,
-    invert: bool,
-    edge_enhancement_level: f32,
-    height_cap: i32,
-    request_billinear: bool,
-    data: Vec<u8>,
-) -> String {
-    set_panic_hook();
-
-    let exif_orientation = parse_exif_orientation(&data);
-    let img = image::load_from_memory(&data).unwrap();
-    let (width, height) = exif_orientation_dimension(img.width(), img.height(), exif_orientation);
-
+fn gb_preset_profile(mode: i32) -> gb::GbDisplayProfile {
     let gb = gb::GbDisplayProfile {
         foreground_r: 19.0 / 255.0,
         foreground_g: 74.0 / 255.0,
@@ -80,6 +76,7 @@ pub fn process_image_gb(
         background_r: 170.0 / 255.0,
         background_g: 181.0 / 255.0,
         background_b: 19.0 / 255.0,
+        shade_opacity: gb::DEFAULT_SHADE_OPACITY,
     };
 
     let gbp = gb::GbDisplayProfile {
@@ -90,6 +87,7 @@ pub fn process_image_gb(
         background_r: 164.0 / 255.0,
         background_g: 169.0 / 255.0,
         background_b: 137.0 / 255.0,
+        shade_opacity: gb::DEFAULT_SHADE_OPACITY,
     };
 
     let gbl = gb::GbDisplayProfile {
@@ -100,14 +98,168 @@ pub fn process_image_gb(
         background_r: 0.0 / 255.0,
         background_g: 181.0 / 255.0,
         background_b: 176.0 / 255.0,
+        shade_opacity: gb::DEFAULT_SHADE_OPACITY,
     };
 
-    let prof = match mode {
+    match mode {
         0 => gb,
         1 => gbp,
         2 => gbl,
         _ => gb,
+    }
+}
+
+// Colours are in the format #RRGGBB and the foreground alpha is a percentage
+fn gb_custom_profile(
+    fg_color: &str,
+    fg_alpha: i32,
+    bg_color: &str,
+    shade_opacity: &[f32],
+) -> Result<gb::GbDisplayProfile, String> {
+    let fg = parse_hex_color(fg_color)?;
+    let bg = parse_hex_color(bg_color)?;
+
+    // Without a calibration, the shades use the default opacities
+    let shade_opacity = match shade_opacity.len() {
+        0 => gb::DEFAULT_SHADE_OPACITY,
+        4 => [
+            shade_opacity[0],
+            shade_opacity[1],
+            shade_opacity[2],
+            shade_opacity[3],
+        ],
+        n => return Err(format!("Expected 4 shade opacities, got {}", n)),
+    };
+
+    Ok(gb::GbDisplayProfile {
+        foreground_r: fg[0] as f32 / 255.0,
+        foreground_g: fg[1] as f32 / 255.0,
+        foreground_b: fg[2] as f32 / 255.0,
+        foreground_a: fg_alpha as f32 / 100.0,
+        background_r: bg[0] as f32 / 255.0,
+        background_g: bg[1] as f32 / 255.0,
+        background_b: bg[2] as f32 / 255.0,
+        shade_opacity,
+    })
+}
+
+// Mode 3 takes the custom colours, like processImageGbCustom, the rest are presets
+fn gb_profile(
+    mode: i32,
+    fg_color: &str,
+    fg_alpha: i32,
+    bg_color: &str,
+    shade_opacity: &[f32],
+) -> Result<gb::GbDisplayProfile, String> {
+    match mode {
+        3 => gb_custom_profile(fg_color, fg_alpha, bg_color, shade_opacity),
+        _ => Ok(gb_preset_profile(mode)),
+    }
+}
+
+// Colour input is in the format #RRGGBB
+fn parse_hex_color(color: &str) -> Result<image::Rgba<u8>, String> {
+    let channel = |range: std::ops::Range<usize>| {
+        color
+            .get(range)
+            .and_then(|v| u8::from_str_radix(v, 16).ok())
+            .ok_or(format!("Invalid colour \"{}\"", color))
+    };
+    if color.len() != 7 || !color.starts_with('#') {
+        return Err(format!("Invalid colour \"{}\"", color));
+    }
+    Ok(image::Rgba([
+        channel(1..3)?,
+        channel(3..5)?,
+        channel(5..7)?,
+        255,
+    ]))
+}
+
+fn output_target(
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: &str,
+) -> Result<OutputTarget, String> {
+    if target_width > MAX_TARGET_SIZE || target_height > MAX_TARGET_SIZE {
+        return Err(format!(
+            "Output size must be at most {}x{}, got {}x{}",
+            MAX_TARGET_SIZE, MAX_TARGET_SIZE, target_width, target_height
+        ));
+    }
+    Ok(OutputTarget {
+        width: target_width,
+        height: target_height,
+        fit: OutputFit::from_u32(fit),
+        canvas: parse_hex_color(canvas_color)?,
+    })
+}
+
+// Negative values keep the subpixel layout of the profile
+fn override_subpixel(layout: &mut gbc::SubpixelLayout, order: i32, orientation: i32, fill: f32) {
+    if order >= 0 {
+        layout.order = gbc::SubpixelOrder::from_u32(order as u32);
+    }
+    if orientation >= 0 {
+        layout.orientation = gbc::SubpixelOrientation::from_u32(orientation as u32);
+    }
+    if fill >= 0.0 {
+        layout.fill = fill;
+    }
+}
+
+fn load_image_full_resolution(data: &[u8]) -> Result<shader_support::FloatImage, String> {
+    let exif_orientation = parse_exif_orientation(data);
+    let img = image::load_from_memory(data)
+        .map_err(|e| format!("Failed to load image: {}", e))?
+        .into_rgba8();
+    let unit_scale = ScaleInfo {
+        scale_x: 1.0,
+        scale_y: 1.0,
+        respect_input_aspect_ratio: false,
+        detected: false,
+        bilinear_recommended: false,
+        device_name: "Unknown".to_string(),
     };
+    Ok(downsample_image_nearest_neighbour(
+        &img,
+        &unit_scale,
+        false,
+        exif_orientation,
+    ))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[wasm_bindgen(js_name = processImageGb)]
+pub fn process_image_gb(
+    mode: i32,
+    dither: bool,
+    brightness: f32,
+    contrast: f32,
+    invert: bool,
+    edge_enhancement_level: f32,
+    height_cap: i32,
+    request_billinear: bool,
+    wear_seed: u32,
+    scratches: f32,
+    dust: f32,
+    dead_pixels: f32,
+    haze: f32,
+    vertical_lines: f32,
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: String,
+    data: Vec<u8>,
+) -> Result<String, String> {
+    set_panic_hook();
+
+    let exif_orientation = parse_exif_orientation(&data);
+    let img = image::load_from_memory(&data).map_err(|e| format!("Failed to load image: {}", e))?;
+    let (width, height) = exif_orientation_dimension(img.width(), img.height(), exif_orientation);
+
+    let prof = gb_preset_profile(mode);
 
     let adjustment = gb::GbColorAdjustment {
         dither,
@@ -136,18 +288,36 @@ pub fn process_image_gb(
         downsample_image_nearest_neighbour(&img, &src_scale, false, exif_orientation)
     };
 
-    let result = gb::gb_mono(&img, &prof, &adjustment);
+    let target = output_target(target_width, target_height, fit, &canvas_color)?;
+
+    let wear = wear::ScreenWear {
+        seed: wear_seed,
+        scratches,
+        dust,
+        dead_pixels,
+        haze,
+        vertical_lines,
+    };
+
+    // Dead pixels follow the LCD grid, lens wear covers the whole rendered screen
+    let shades = wear::wear_shades(&gb::gb_mono_quantize(&img, &adjustment), &wear);
+    let result = wear::wear_lens(&gb::gb_mono_shades(&shades, &prof), &wear);
+
+    // The GB renderer has a fixed scale, so the target size is reached by resampling afterwards
+    let result = fit_to_target(&result, &target);
 
     let mut buf = Vec::new();
     let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
-    return general_purpose::STANDARD.encode(&buf);
+    Ok(general_purpose::STANDARD.encode(&buf))
 }
 
+#[allow(clippy::too_many_arguments)]
 #[wasm_bindgen(js_name = processImageGbCustom)]
 pub fn process_image_gb_custom(
     fg_color: String,
     fg_alpha: i32,
     bg_color: String,
+    shade_opacity: Vec<f32>,
     dither: bool,
     brightness: f32,
     contrast: f32,
@@ -155,24 +325,25 @@ pub fn process_image_gb_custom(
     edge_enhancement_level: f32,
     height_cap: i32,
     request_billinear: bool,
+    wear_seed: u32,
+    scratches: f32,
+    dust: f32,
+    dead_pixels: f32,
+    haze: f32,
+    vertical_lines: f32,
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: String,
     data: Vec<u8>,
-) -> String {
+) -> Result<String, String> {
     set_panic_hook();
 
-    let img = image::load_from_memory(&data).unwrap();
+    let img = image::load_from_memory(&data).map_err(|e| format!("Failed to load image: {}", e))?;
     let exif_orientation = parse_exif_orientation(&data);
     let (width, height) = exif_orientation_dimension(img.width(), img.height(), exif_orientation);
 
-    // Color input is in the format #RRGGBB
-    let prof = gb::GbDisplayProfile {
-        foreground_r: i32::from_str_radix(&fg_color[1..3], 16).unwrap() as f32 / 255.0,
-        foreground_g: i32::from_str_radix(&fg_color[3..5], 16).unwrap() as f32 / 255.0,
-        foreground_b: i32::from_str_radix(&fg_color[5..7], 16).unwrap() as f32 / 255.0,
-        foreground_a: fg_alpha as f32 / 100.0,
-        background_r: i32::from_str_radix(&bg_color[1..3], 16).unwrap() as f32 / 255.0,
-        background_g: i32::from_str_radix(&bg_color[3..5], 16).unwrap() as f32 / 255.0,
-        background_b: i32::from_str_radix(&bg_color[5..7], 16).unwrap() as f32 / 255.0,
-    };
+    let prof = gb_custom_profile(&fg_color, fg_alpha, &bg_color, &shade_opacity)?;
 
     let adjustment = gb::GbColorAdjustment {
         dither,
@@ -201,111 +372,777 @@ pub fn process_image_gb_custom(
         downsample_image_nearest_neighbour(&img, &src_scale, false, exif_orientation)
     };
 
-    let result = gb::gb_mono(&img, &prof, &adjustment);
+    let target = output_target(target_width, target_height, fit, &canvas_color)?;
+
+    let wear = wear::ScreenWear {
+        seed: wear_seed,
+        scratches,
+        dust,
+        dead_pixels,
+        haze,
+        vertical_lines,
+    };
+
+    // Dead pixels follow the LCD grid, lens wear covers the whole rendered screen
+    let shades = wear::wear_shades(&gb::gb_mono_quantize(&img, &adjustment), &wear);
+    let result = wear::wear_lens(&gb::gb_mono_shades(&shades, &prof), &wear);
+
+    // The GB renderer has a fixed scale, so the target size is reached by resampling afterwards
+    let result = fit_to_target(&result, &target);
 
     let mut buf = Vec::new();
     let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
-    return general_purpose::STANDARD.encode(&buf);
+    Ok(general_purpose::STANDARD.encode(&buf))
 }
 
+#[wasm_bindgen(getter_with_clone)]
+pub struct GbPaletteEstimate {
+    #[wasm_bindgen(js_name = fgColor)]
+    pub fg_color: String,
+    // 0 to 100, like the custom palette input
+    #[wasm_bindgen(js_name = fgAlpha)]
+    pub fg_alpha: i32,
+    #[wasm_bindgen(js_name = bgColor)]
+    pub bg_color: String,
+    #[wasm_bindgen(js_name = shadeOpacity)]
+    pub shade_opacity: Vec<f32>,
+    // Shades whose opacity was measured, the others keep their defaults
+    #[wasm_bindgen(js_name = estimatedShades)]
+    pub estimated_shades: Vec<u32>,
+    pub confidence: f32,
+}
+
+#[wasm_bindgen(js_name = estimateGbPalette)]
+pub fn estimate_gb_palette(data: Vec<u8>) -> Result<GbPaletteEstimate, String> {
+    set_panic_hook();
+
+    let photo = load_image_full_resolution(&data)?;
+
+    let estimate = gb_palette::estimate_gb_palette(&photo)?;
+    let p = &estimate.profile;
+    let hex = |r: f32, g: f32, b: f32| {
+        format!(
+            "#{:02x}{:02x}{:02x}",
+            float_to_byte(r),
+            float_to_byte(g),
+            float_to_byte(b)
+        )
+    };
+
+    Ok(GbPaletteEstimate {
+        fg_color: hex(p.foreground_r, p.foreground_g, p.foreground_b),
+        fg_alpha: (p.foreground_a * 100.0).round() as i32,
+        bg_color: hex(p.background_r, p.background_g, p.background_b),
+        shade_opacity: p.shade_opacity.to_vec(),
+        estimated_shades: (0..4)
+            .filter(|&shade| estimate.estimated_shades[shade as usize])
+            .collect(),
+        confidence: estimate.confidence,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+#[wasm_bindgen(js_name = processImageGbCamera)]
+pub fn process_image_gb_camera(
+    mode: i32,
+    fg_color: String,
+    fg_alpha: i32,
+    bg_color: String,
+    shade_opacity: Vec<f32>,
+    layout: u32,
+    dither: bool,
+    brightness: f32,
+    contrast: f32,
+    invert: bool,
+    edge_enhancement_level: f32,
+    crop_x: f32,
+    crop_y: f32,
+    crop_width: f32,
+    crop_height: f32,
+    fit: u32,
+    frame: u32,
+    frame_data: Vec<u8>,
+    target_width: u32,
+    target_height: u32,
+    output_fit: u32,
+    canvas_color: String,
+    data: Vec<u8>,
+    data_lengths: Vec<u32>,
+) -> Result<String, String> {
+    set_panic_hook();
+
+    let target = output_target(target_width, target_height, output_fit, &canvas_color)?;
+
+    // Multiple photos are packed back to back in a single buffer
+    let mut photos = Vec::new();
+    let mut offset = 0usize;
+    for len in data_lengths {
+        let end = offset.saturating_add(len as usize);
+        let photo = data.get(offset..end).ok_or(format!(
+            "Photo lengths add up to more than the {} bytes of data",
+            data.len()
+        ))?;
+        photos.push(load_image_full_resolution(photo)?);
+        offset = end;
+    }
+    if offset != data.len() {
+        return Err(format!(
+            "Photo lengths add up to {} bytes, but there are {} bytes of data",
+            offset,
+            data.len()
+        ));
+    }
+
+    let frame = match frame {
+        1 => gb::GbCameraFrame::Plain,
+        2 => gb::GbCameraFrame::Film,
+        3 => {
+            let frame_img = image::load_from_memory(&frame_data)
+                .map_err(|e| format!("Failed to load frame image: {}", e))?
+                .into_rgba8();
+            if frame_img.width() != gb::GB_FRAME_WIDTH || frame_img.height() != gb::GB_FRAME_HEIGHT
+            {
+                return Err(format!(
+                    "Frame image must be {}x{}, got {}x{}",
+                    gb::GB_FRAME_WIDTH,
+                    gb::GB_FRAME_HEIGHT,
+                    frame_img.width(),
+                    frame_img.height()
+                ));
+            }
+            gb::GbCameraFrame::Custom(gb::shades_from_image(&frame_img))
+        }
+        _ => gb::GbCameraFrame::None,
+    };
+
+    let prof = gb_profile(mode, &fg_color, fg_alpha, &bg_color, &shade_opacity)?;
+
+    let adjustment = gb::GbColorAdjustment {
+        dither,
+        brightness,
+        contrast,
+        invert,
+        edge_enhancement_level,
+    };
+
+    let crop = gb::GbCameraCrop {
+        x: crop_x,
+        y: crop_y,
+        width: crop_width,
+        height: crop_height,
+    };
+
+    let img = gb::gb_camera_compose(
+        &photos,
+        &gb::GbCameraLayout::from_u32(layout),
+        &crop,
+        &gb::GbCameraFit::from_u32(fit),
+    );
+    let shades = gb::gb_camera_framed(&img, &frame, &adjustment);
+
+    let result = fit_to_target(&gb::gb_mono_shades(&shades, &prof), &target);
+
+    let mut buf = Vec::new();
+    let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
+    Ok(general_purpose::STANDARD.encode(&buf))
+}
+
+#[wasm_bindgen(js_name = getGbCameraSaveSlots)]
+pub fn get_gb_camera_save_slots(data: Vec<u8>) -> Result<Vec<i32>, String> {
+    set_panic_hook();
+
+    // Album index for each slot, or -1 if the slot is deleted
+    let save = gb_camera_save::GbCameraSave::parse(data)?;
+    Ok((0..gb_camera_save::GB_CAMERA_PHOTO_SLOTS)
+        .map(|slot| match save.slot_state(slot) {
+            gb_camera_save::GbCameraSlotState::Active(index) => index as i32,
+            gb_camera_save::GbCameraSlotState::Deleted => -1,
+        })
+        .collect())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[wasm_bindgen(js_name = processGbCameraSavePhoto)]
+pub fn process_gb_camera_save_photo(
+    mode: i32,
+    fg_color: String,
+    fg_alpha: i32,
+    bg_color: String,
+    shade_opacity: Vec<f32>,
+    slot: i32,
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: String,
+    data: Vec<u8>,
+) -> Result<String, String> {
+    set_panic_hook();
+
+    let prof = gb_profile(mode, &fg_color, fg_alpha, &bg_color, &shade_opacity)?;
+    let target = output_target(target_width, target_height, fit, &canvas_color)?;
+
+    let save = gb_camera_save::GbCameraSave::parse(data)?;
+    let shades = match slot {
+        -1 => save.last_seen(),
+        0..=29 => save.photo(slot as usize),
+        _ => return Err(format!("Invalid GB Camera photo slot {}", slot)),
+    };
+
+    let result = fit_to_target(&gb::gb_mono_shades(&shades, &prof), &target);
+
+    let mut buf = Vec::new();
+    let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
+    Ok(general_purpose::STANDARD.encode(&buf))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[wasm_bindgen(js_name = processGbCameraSaveRoll)]
+pub fn process_gb_camera_save_roll(
+    mode: i32,
+    fg_color: String,
+    fg_alpha: i32,
+    bg_color: String,
+    shade_opacity: Vec<f32>,
+    include_deleted: bool,
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: String,
+    data: Vec<u8>,
+) -> Result<Vec<String>, String> {
+    set_panic_hook();
+
+    let prof = gb_profile(mode, &fg_color, fg_alpha, &bg_color, &shade_opacity)?;
+    let target = output_target(target_width, target_height, fit, &canvas_color)?;
+    let save = gb_camera_save::GbCameraSave::parse(data)?;
+
+    Ok(save
+        .roll(include_deleted)
+        .into_iter()
+        .map(|slot| {
+            let result = fit_to_target(&gb::gb_mono_shades(&save.photo(slot), &prof), &target);
+            let mut buf = Vec::new();
+            let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
+            general_purpose::STANDARD.encode(&buf)
+        })
+        .collect())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[wasm_bindgen(js_name = processGbPrinterLog)]
+pub fn process_gb_printer_log(
+    style: u32,
+    mode: i32,
+    fg_color: String,
+    fg_alpha: i32,
+    bg_color: String,
+    shade_opacity: Vec<f32>,
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: String,
+    log: String,
+) -> Result<Vec<String>, String> {
+    set_panic_hook();
+
+    let prof = gb_profile(mode, &fg_color, fg_alpha, &bg_color, &shade_opacity)?;
+    let target = output_target(target_width, target_height, fit, &canvas_color)?;
+    let printer_log = gb_printer::decode_printer_log(&log);
+
+    Ok(printer_log
+        .images
+        .iter()
+        .map(|printed| {
+            let result = match style {
+                1 => gb_printer::printer_render(printed),
+                _ => gb::gb_mono_shades(&printed.shades, &prof),
+            };
+            let result = fit_to_target(&result, &target);
+            let mut buf = Vec::new();
+            let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
+            general_purpose::STANDARD.encode(&buf)
+        })
+        .collect())
+}
+
+#[wasm_bindgen(js_name = getGbPrinterLogIssues)]
+pub fn get_gb_printer_log_issues(log: String) -> Vec<String> {
+    set_panic_hook();
+
+    gb_printer::decode_printer_log(&log)
+        .issues
+        .iter()
+        .map(|issue| issue.to_string())
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+#[wasm_bindgen(js_name = processGbTiles)]
+pub fn process_gb_tiles(
+    mode: i32,
+    fg_color: String,
+    fg_alpha: i32,
+    bg_color: String,
+    shade_opacity: Vec<f32>,
+    bgp: u8,
+    width: u32,
+    height: u32,
+    scroll_x: u32,
+    scroll_y: u32,
+    signed_addressing: bool,
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: String,
+    tiles: Vec<u8>,
+    tilemap: Vec<u8>,
+) -> Result<String, String> {
+    set_panic_hook();
+
+    let prof = gb_profile(mode, &fg_color, fg_alpha, &bg_color, &shade_opacity)?;
+    let target = output_target(target_width, target_height, fit, &canvas_color)?;
+
+    // An empty tilemap lays out the tiles in order
+    let tilemap = match tilemap.len() {
+        0 => None,
+        gb_tiles::TILEMAP_SIZE => Some(tilemap.as_slice()),
+        len => {
+            return Err(format!(
+                "Tilemap must be {} bytes, got {}",
+                gb_tiles::TILEMAP_SIZE,
+                len
+            ))
+        }
+    };
+
+    // Zero size falls back to the GB screen, or to however many rows the tile data needs
+    let width = if width == 0 { 160 } else { width };
+    let height = match (height, tilemap) {
+        (0, Some(_)) => 144,
+        (0, None) => ((tiles.len() as u32)
+            .div_ceil(16)
+            .div_ceil(width.div_ceil(8))
+            * 8)
+        .max(8),
+        (height, _) => height,
+    };
+    if width > gb_tiles::MAX_SIZE || height > gb_tiles::MAX_SIZE {
+        return Err(format!(
+            "Output must be at most {}x{}, got {}x{}",
+            gb_tiles::MAX_SIZE,
+            gb_tiles::MAX_SIZE,
+            width,
+            height
+        ));
+    }
+
+    let layout = gb_tiles::TileLayout {
+        width,
+        height,
+        scroll_x,
+        scroll_y,
+        signed_addressing,
+    };
+
+    let shades = gb_tiles::render_tiles(&tiles, tilemap, bgp, &layout);
+
+    let result = fit_to_target(&gb::gb_mono_shades(&shades, &prof), &target);
+
+    let mut buf = Vec::new();
+    let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
+    Ok(general_purpose::STANDARD.encode(&buf))
+}
+
+#[allow(clippy::too_many_arguments)]
 #[wasm_bindgen(js_name = processImageGbc)]
 pub fn process_image_gbc(
     scale: u32,
     lcd_mode: u32,
+    grid_strength: f32,
+    subpixel_order: i32,
+    subpixel_orientation: i32,
+    subpixel_fill: f32,
     color_mode: u32,
     height_cap: i32,
     request_billinear: bool,
+    lut_mode: u32,
+    lut_interpolation: u32,
+    lut_data: Vec<u8>,
+    ambient_light: bool,
+    ambient_brightness: f32,
+    ambient_temperature: f32,
+    glare: f32,
+    front_light: bool,
+    viewing_angle: bool,
+    view_vertical: f32,
+    view_horizontal: f32,
+    tilt_gradient: f32,
+    quantize_mode: u32,
+    quantize_dither: u32,
+    brightness: f32,
+    contrast: f32,
+    saturation: f32,
+    hue: f32,
+    temperature: f32,
+    tint: f32,
+    correction_strength: f32,
+    wear_seed: u32,
+    scratches: f32,
+    dust: f32,
+    dead_pixels: f32,
+    haze: f32,
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: String,
     data: Vec<u8>,
-) -> String {
+) -> Result<String, String> {
     set_panic_hook();
 
-    // Pokefan531's GBC Display Profile
-    let gbc = gbc::DisplayProfile {
-        r: 0.80,
-        gr: 0.275,
-        br: -0.075,
-        rg: 0.135,
-        g: 0.64,
-        bg: 0.225,
-        rb: 0.195,
-        gb: 0.155,
-        b: 0.65,
-        lum: 0.93,
-        gamma: 2.2,
-        gamma_offset: -0.5,
-        bgr: false,
-    };
-
-    let gba = gbc::DisplayProfile {
-        r: 0.80,
-        gr: 0.275,
-        br: -0.075,
-        rg: 0.135,
-        g: 0.64,
-        bg: 0.225,
-        rb: 0.195,
-        gb: 0.155,
-        b: 0.65,
-        lum: 0.93,
-        gamma: 2.0,
-        gamma_offset: 0.5,
-        bgr: true,
-    };
-
-    let gba_sp = gbc::DisplayProfile {
-        r: 0.86,
-        gr: 0.10,
-        br: -0.06,
-        rg: 0.03,
-        g: 0.745,
-        bg: 0.0675,
-        rb: 0.0025,
-        gb: -0.03,
-        b: 1.0275,
-        lum: 0.97,
-        gamma: 2.0,
-        gamma_offset: 0.0,
-        bgr: false,
-    };
-
-    let gba_sp_white = gbc::DisplayProfile {
-        r: 0.955,
-        gr: 0.11,
-        br: -0.065,
-        rg: 0.0375,
-        g: 0.885,
-        bg: 0.0775,
-        rb: 0.0025,
-        gb: -0.03,
-        b: 1.0275,
-        lum: 0.94,
-        gamma: 2.0,
-        gamma_offset: 0.0,
-        bgr: false,
-    };
-
-    let prof = match color_mode {
-        0 => gbc,
-        1 => gba,
-        2 => gba_sp,
-        3 => gba_sp_white,
-        _ => gbc,
+    let target = output_target(target_width, target_height, fit, &canvas_color)?;
+
+    let mut prof = gbc::display_profile_preset(color_mode);
+
+    override_subpixel(
+        &mut prof.subpixel,
+        subpixel_order,
+        subpixel_orientation,
+        subpixel_fill,
+    );
+
+    // An empty LUT means the display profile alone is used
+    let color_lut = if lut_data.is_empty() {
+        None
+    } else {
+        Some(gbc::ColorLut {
+            lut: lut::Lut3d::load(&lut_data)?,
+            stage: gbc::LutStage::from_u32(lut_mode),
+            interpolation: lut::LutInterpolation::from_u32(lut_interpolation),
+        })
     };
 
     let fallback_height = if height_cap < 0 {
-        match color_mode {
-            0 => 144,
-            1 => 160,
-            2 => 160,
-            3 => 160,
-            _ => 144,
+        prof.native_height
+    } else {
+        height_cap as u32
+    };
+
+    let exif_orientation = parse_exif_orientation(&data);
+    let img = image::load_from_memory(&data).map_err(|e| format!("Failed to load image: {}", e))?;
+    let (width, height) = exif_orientation_dimension(img.width(), img.height(), exif_orientation);
+
+    let src_scale = detect_src_scale(width, height, fallback_height);
+
+    let use_bilinear = src_scale.bilinear_recommended && request_billinear;
+
+    let img = img.into_rgba8();
+    let img = if use_bilinear {
+        downsample_image_bilinear(&img, &src_scale, false, exif_orientation)
+    } else {
+        downsample_image_nearest_neighbour(&img, &src_scale, false, exif_orientation)
+    };
+
+    let adjustment = color_adjust::ColorAdjustment {
+        brightness,
+        contrast,
+        saturation,
+        hue,
+        temperature,
+        tint,
+        correction_strength,
+    };
+
+    // Adjustments edit the source colours, so they happen before the LCD pass
+    let img = if adjustment.is_identity() {
+        img
+    } else {
+        color_adjust::adjust_image(&img, &adjustment)
+    };
+
+    // Reduce to the colours the hardware can output before the display profile is applied
+    let img = match quantize_mode {
+        0 => img,
+        mode => quantize::quantize_rgb555(
+            &img,
+            quantize::ChannelResponse::from_u32(mode - 1),
+            quantize::QuantizeDither::from_u32(quantize_dither),
+        ),
+    };
+
+    // Colour panels have no ribbon cable lines
+    let wear = wear::ScreenWear {
+        seed: wear_seed,
+        scratches,
+        dust,
+        dead_pixels,
+        haze,
+        vertical_lines: 0.0,
+    };
+    let img = if dead_pixels > 0.0 {
+        wear::wear_pixels(&img, &wear)
+    } else {
+        img
+    };
+
+    let lcd = gbc::LcdParams {
+        mode: gbc::LcdMode::from_u32(lcd_mode),
+        grid_strength,
+    };
+
+    // Without ambient light, every panel is rendered as if evenly lit
+    let ambient = if ambient_light {
+        Some(gbc::AmbientLight {
+            brightness: ambient_brightness,
+            temperature: ambient_temperature,
+            glare,
+            front_light,
+        })
+    } else {
+        None
+    };
+
+    // Without a viewing angle, the screen is seen head on
+    let viewing = if viewing_angle {
+        Some(gbc::ViewingAngle {
+            vertical: view_vertical,
+            horizontal: view_horizontal,
+            tilt_gradient,
+        })
+    } else {
+        None
+    };
+
+    // The shaders render straight at the target size, even if it is not an integer scale
+    let (width, height) = if target.is_set() {
+        target.content_size(img.width(), img.height())
+    } else {
+        (img.width() * scale, img.height() * scale)
+    };
+
+    let result = gbc::color_gb(
+        &img,
+        width,
+        height,
+        &lcd,
+        &prof,
+        adjustment.correction_strength,
+        color_lut.as_ref(),
+        ambient.as_ref(),
+        viewing.as_ref(),
+    );
+
+    let result = wear::wear_lens(&result, &wear);
+
+    let result = fit_to_target(&result, &target);
+
+    let mut buf = Vec::new();
+    let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
+    Ok(general_purpose::STANDARD.encode(&buf))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[wasm_bindgen(js_name = processImageGbcColorize)]
+pub fn process_image_gbc_colorize(
+    palette: i32,
+    title: String,
+    nintendo_licensed: bool,
+    scale: u32,
+    lcd_mode: u32,
+    grid_strength: f32,
+    subpixel_order: i32,
+    subpixel_orientation: i32,
+    subpixel_fill: f32,
+    height_cap: i32,
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: String,
+    obj_mask: Vec<u8>,
+    data: Vec<u8>,
+) -> Result<String, String> {
+    set_panic_hook();
+
+    let target = output_target(target_width, target_height, fit, &canvas_color)?;
+
+    // Negative values look the palette up from the game title, like the boot ROM does
+    let combination = if palette < 0 {
+        gbc_colorize::combination_for_title(&title, nintendo_licensed)
+    } else {
+        *gbc_colorize::BUTTON_COMBINATIONS
+            .get(palette as usize)
+            .ok_or(format!("Unknown palette {}", palette))?
+    };
+    let palette = gbc_colorize::BootPalette::from_combination(combination).unwrap();
+
+    // The colourized frame is shown on a GBC screen
+    let mut prof = gbc::display_profile_preset(0);
+
+    override_subpixel(
+        &mut prof.subpixel,
+        subpixel_order,
+        subpixel_orientation,
+        subpixel_fill,
+    );
+
+    let exif_orientation = parse_exif_orientation(&data);
+    let img = image::load_from_memory(&data).map_err(|e| format!("Failed to load image: {}", e))?;
+    let (width, height) = exif_orientation_dimension(img.width(), img.height(), exif_orientation);
+
+    let src_scale = detect_src_scale(
+        width,
+        height,
+        if height_cap < 0 {
+            144
+        } else {
+            height_cap as u32
+        },
+    );
+
+    // Shades and layers are told apart by exact colours, so there is no bilinear option
+    let exact_colors = |img: &image::RgbaImage, exif_orientation: u32| {
+        let img = downsample_image_nearest_neighbour(img, &src_scale, true, exif_orientation);
+        image::RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+            let c = img.get_pixel(x, y);
+            image::Rgba([
+                float_to_byte(c[0]),
+                float_to_byte(c[1]),
+                float_to_byte(c[2]),
+                255,
+            ])
+        })
+    };
+    let shades = gb::shades_from_image(&exact_colors(&img.into_rgba8(), exif_orientation));
+
+    // An empty mask puts everything on the background layer
+    let layers = if obj_mask.is_empty() {
+        None
+    } else {
+        let mask = image::load_from_memory(&obj_mask)
+            .map_err(|e| format!("Failed to load the OBJ mask: {}", e))?
+            .into_rgba8();
+        if (mask.width(), mask.height()) != (width, height) {
+            return Err(format!(
+                "OBJ mask is {}x{}, but the screenshot is {}x{}",
+                mask.width(),
+                mask.height(),
+                width,
+                height
+            ));
         }
+        Some(gbc_colorize::layers_from_mask(&exact_colors(&mask, 1)))
+    };
+
+    let img = gbc_colorize::colorize(&shades, layers.as_deref(), &palette);
+
+    let lcd = gbc::LcdParams {
+        mode: gbc::LcdMode::from_u32(lcd_mode),
+        grid_strength,
+    };
+
+    let (width, height) = if target.is_set() {
+        target.content_size(img.width(), img.height())
+    } else {
+        (img.width() * scale, img.height() * scale)
+    };
+
+    let result = gbc::color_gb(&img, width, height, &lcd, &prof, 1.0, None, None, None);
+
+    let result = fit_to_target(&result, &target);
+
+    let mut buf = Vec::new();
+    let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
+    Ok(general_purpose::STANDARD.encode(&buf))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[wasm_bindgen(js_name = processImageGbOnGba)]
+pub fn process_image_gb_on_gba(
+    display_mode: u32,
+    scale: u32,
+    lcd_mode: u32,
+    grid_strength: f32,
+    subpixel_order: i32,
+    subpixel_orientation: i32,
+    subpixel_fill: f32,
+    color_mode: u32,
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: String,
+    data: Vec<u8>,
+) -> Result<String, String> {
+    set_panic_hook();
+
+    let target = output_target(target_width, target_height, fit, &canvas_color)?;
+
+    let mut prof = gbc::display_profile_preset(color_mode);
+
+    override_subpixel(
+        &mut prof.subpixel,
+        subpixel_order,
+        subpixel_orientation,
+        subpixel_fill,
+    );
+
+    let exif_orientation = parse_exif_orientation(&data);
+    let img = image::load_from_memory(&data).map_err(|e| format!("Failed to load image: {}", e))?;
+    let (width, height) = exif_orientation_dimension(img.width(), img.height(), exif_orientation);
+
+    // The source is always a GB or GBC frame, whatever the screen it ends up on
+    let src_scale = detect_src_scale(width, height, gb::GB_FRAME_HEIGHT);
+    let img =
+        downsample_image_nearest_neighbour(&img.into_rgba8(), &src_scale, false, exif_orientation);
+
+    let img = gb_on_gba::gb_on_gba(&img, &gb_on_gba::GbaDisplayMode::from_u32(display_mode))?;
+
+    let lcd = gbc::LcdParams {
+        mode: gbc::LcdMode::from_u32(lcd_mode),
+        grid_strength,
+    };
+
+    let (width, height) = if target.is_set() {
+        target.content_size(img.width(), img.height())
+    } else {
+        (img.width() * scale, img.height() * scale)
+    };
+
+    let result = gbc::color_gb(&img, width, height, &lcd, &prof, 1.0, None, None, None);
+
+    let result = fit_to_target(&result, &target);
+
+    let mut buf = Vec::new();
+    let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
+    Ok(general_purpose::STANDARD.encode(&buf))
+}
+
+#[wasm_bindgen(getter_with_clone)]
+pub struct InverseCorrectionResult {
+    // Raw colours at native resolution, ready to be rendered with any colour mode
+    pub image: String,
+    pub clipped: f32,
+    #[wasm_bindgen(js_name = outOfRange)]
+    pub out_of_range: f32,
+}
+
+#[wasm_bindgen(js_name = invertColorCorrection)]
+pub fn invert_color_correction(
+    color_mode: u32,
+    height_cap: i32,
+    request_billinear: bool,
+    data: Vec<u8>,
+) -> Result<InverseCorrectionResult, String> {
+    set_panic_hook();
+
+    let prof = gbc::display_profile_preset(color_mode);
+
+    let fallback_height = if height_cap < 0 {
+        prof.native_height
     } else {
         height_cap as u32
     };
 
     let exif_orientation = parse_exif_orientation(&data);
-    let img = image::load_from_memory(&data).unwrap();
+    let img = image::load_from_memory(&data).map_err(|e| format!("Failed to load image: {}", e))?;
     let (width, height) = exif_orientation_dimension(img.width(), img.height(), exif_orientation);
 
     let src_scale = detect_src_scale(width, height, fallback_height);
@@ -319,13 +1156,231 @@ pub fn process_image_gbc(
         downsample_image_nearest_neighbour(&img, &src_scale, false, exif_orientation)
     };
 
-    let result = gbc::color_gb(&img, scale, lcd_mode, &prof);
+    let inverse = gbc::invert_color_correction(&img, &prof)?;
+
+    let result = image::RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+        let color = inverse.image.get_pixel(x, y).to_gamma();
+        image::Rgba([
+            float_to_byte(color[0]),
+            float_to_byte(color[1]),
+            float_to_byte(color[2]),
+            255,
+        ])
+    });
 
     let mut buf = Vec::new();
     let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
-    return general_purpose::STANDARD.encode(&buf);
+    Ok(InverseCorrectionResult {
+        image: general_purpose::STANDARD.encode(&buf),
+        clipped: inverse.clipped,
+        out_of_range: inverse.out_of_range,
+    })
+}
+
+#[wasm_bindgen]
+pub struct ProfileCoefficients {
+    pub r: f32,
+    pub gr: f32,
+    pub br: f32,
+    pub rg: f32,
+    pub g: f32,
+    pub bg: f32,
+    pub rb: f32,
+    pub gb: f32,
+    pub b: f32,
+    pub lum: f32,
+    pub gamma: f32,
+    #[wasm_bindgen(js_name = gammaOffset)]
+    pub gamma_offset: f32,
+}
+
+#[wasm_bindgen]
+pub struct ProfileMeasurement {
+    #[wasm_bindgen(js_name = redX)]
+    pub red_x: f32,
+    #[wasm_bindgen(js_name = redY)]
+    pub red_y: f32,
+    #[wasm_bindgen(js_name = greenX)]
+    pub green_x: f32,
+    #[wasm_bindgen(js_name = greenY)]
+    pub green_y: f32,
+    #[wasm_bindgen(js_name = blueX)]
+    pub blue_x: f32,
+    #[wasm_bindgen(js_name = blueY)]
+    pub blue_y: f32,
+    #[wasm_bindgen(js_name = whiteX)]
+    pub white_x: f32,
+    #[wasm_bindgen(js_name = whiteY)]
+    pub white_y: f32,
+    #[wasm_bindgen(js_name = peakLuminance)]
+    pub peak_luminance: f32,
+    pub gamma: f32,
+}
+
+// The colour mode supplies the target gamma, the measurement everything else
+#[allow(clippy::too_many_arguments)]
+#[wasm_bindgen(js_name = deriveDisplayProfile)]
+pub fn derive_display_profile(
+    color_mode: u32,
+    red_x: f32,
+    red_y: f32,
+    green_x: f32,
+    green_y: f32,
+    blue_x: f32,
+    blue_y: f32,
+    white_x: f32,
+    white_y: f32,
+    peak_luminance: f32,
+    gamma: f32,
+    adapt_white: bool,
+) -> Result<ProfileCoefficients, String> {
+    set_panic_hook();
+
+    let chromaticity = |x, y| colorimetry::Chromaticity { x, y };
+    let measurement = colorimetry::PanelMeasurement {
+        red: chromaticity(red_x, red_y),
+        green: chromaticity(green_x, green_y),
+        blue: chromaticity(blue_x, blue_y),
+        white: chromaticity(white_x, white_y),
+        peak_luminance,
+        gamma,
+    };
+    let prof =
+        gbc::display_profile_preset(color_mode).with_measurement(&measurement, adapt_white)?;
+
+    Ok(profile_coefficients(&prof))
+}
+
+fn profile_coefficients(prof: &gbc::DisplayProfile) -> ProfileCoefficients {
+    ProfileCoefficients {
+        r: prof.r,
+        gr: prof.gr,
+        br: prof.br,
+        rg: prof.rg,
+        g: prof.g,
+        bg: prof.bg,
+        rb: prof.rb,
+        gb: prof.gb,
+        b: prof.b,
+        lum: prof.lum,
+        gamma: prof.gamma,
+        gamma_offset: prof.gamma_offset,
+    }
+}
+
+pub use profile_fit::PatchError;
+
+pub struct ProfileFitReport {
+    pub profile: ProfileCoefficients,
+    pub patches: Vec<PatchError>,
+    pub mean_delta_e: f32,
+    pub max_delta_e: f32,
 }
 
+// Native entry point for display-boy-cli, which keeps the fitting internals private
+pub fn fit_display_profile(
+    pairs: &[(image::RgbaImage, image::RgbaImage)],
+    color_mode: u32,
+) -> Result<ProfileFitReport, String> {
+    let fit = profile_fit::fit_display_profile(pairs, color_mode)?;
+    Ok(ProfileFitReport {
+        profile: profile_coefficients(&fit.profile),
+        patches: fit.patches,
+        mean_delta_e: fit.mean_delta_e,
+        max_delta_e: fit.max_delta_e,
+    })
+}
+
+// Patch colours of the fit are in linear light, this gives their 8 bit sRGB values
+pub fn linear_to_srgb8(c: image::Rgb<f32>) -> image::Rgb<u8> {
+    let c = c.clamp01().to_gamma();
+    image::Rgb([
+        float_to_byte(c[0]),
+        float_to_byte(c[1]),
+        float_to_byte(c[2]),
+    ])
+}
+
+#[wasm_bindgen(js_name = measureDisplayProfile)]
+pub fn measure_display_profile(color_mode: u32) -> Result<ProfileMeasurement, String> {
+    set_panic_hook();
+
+    let m = gbc::display_profile_preset(color_mode).measurement()?;
+
+    Ok(ProfileMeasurement {
+        red_x: m.red.x,
+        red_y: m.red.y,
+        green_x: m.green.x,
+        green_y: m.green.y,
+        blue_x: m.blue.x,
+        blue_y: m.blue.y,
+        white_x: m.white.x,
+        white_y: m.white.y,
+        peak_luminance: m.peak_luminance,
+        gamma: m.gamma,
+    })
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct CrtSettings {
+    pub margin: u32,
+    #[wasm_bindgen(js_name = scanlineWeight)]
+    pub scanline_weight: f32,
+    pub lum: f32,
+    #[wasm_bindgen(js_name = maskStrength)]
+    pub mask_strength: f32,
+    // 0 dot, 1 aperture grille, 2 slot, 3 shadow, 4 RGB stripe
+    #[wasm_bindgen(js_name = maskType)]
+    pub mask_type: u32,
+    // 0 output, 1 source
+    #[wasm_bindgen(js_name = maskResolution)]
+    pub mask_resolution: u32,
+    #[wasm_bindgen(js_name = maskCompensation)]
+    pub mask_compensation: f32,
+    pub gamma: f32,
+    #[wasm_bindgen(js_name = lanczosSize)]
+    pub lanczos_size: i32,
+    #[wasm_bindgen(js_name = curvatureX)]
+    pub curvature_x: f32,
+    #[wasm_bindgen(js_name = curvatureY)]
+    pub curvature_y: f32,
+    #[wasm_bindgen(js_name = cornerRadius)]
+    pub corner_radius: f32,
+    #[wasm_bindgen(js_name = cornerSmoothness)]
+    pub corner_smoothness: f32,
+    pub overscan: f32,
+    #[wasm_bindgen(js_name = tiltX)]
+    pub tilt_x: f32,
+    #[wasm_bindgen(js_name = tiltY)]
+    pub tilt_y: f32,
+}
+
+// 0 is the default look, followed by consumer TV, PVM, BVM and arcade
+#[wasm_bindgen(js_name = crtPreset)]
+pub fn crt_preset(preset: u32) -> CrtSettings {
+    let p = crt::CrtParams::preset(preset);
+    CrtSettings {
+        margin: p.margin,
+        scanline_weight: p.scanline_weight,
+        lum: p.lum,
+        mask_strength: p.mask_strength,
+        mask_type: p.mask.to_u32(),
+        mask_resolution: p.mask_resolution.to_u32(),
+        mask_compensation: p.mask_compensation,
+        gamma: p.gamma,
+        lanczos_size: p.lanczos_size,
+        curvature_x: p.geometry.curvature_x,
+        curvature_y: p.geometry.curvature_y,
+        corner_radius: p.geometry.corner_radius,
+        corner_smoothness: p.geometry.corner_smoothness,
+        overscan: p.geometry.overscan,
+        tilt_x: p.geometry.tilt_x,
+        tilt_y: p.geometry.tilt_y,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 #[wasm_bindgen(js_name = processImageCrt)]
 pub fn process_image_crt(
     scale: u32,
@@ -333,12 +1388,59 @@ pub fn process_image_crt(
     pixel_aspect_ratio: f32,
     height_cap: i32,
     request_billinear: bool,
+    settings: &CrtSettings,
+    identity_lut: bool,
+    lut_data: Vec<u8>,
+    brightness: f32,
+    contrast: f32,
+    saturation: f32,
+    hue: f32,
+    temperature: f32,
+    tint: f32,
+    correction_strength: f32,
+    target_width: u32,
+    target_height: u32,
+    fit: u32,
+    canvas_color: String,
     data: Vec<u8>,
-) -> String {
+) -> Result<String, String> {
     set_panic_hook();
 
+    let target = output_target(target_width, target_height, fit, &canvas_color)?;
+
+    let params = crt::CrtParams {
+        margin: settings.margin,
+        scanline_weight: settings.scanline_weight,
+        lum: settings.lum,
+        mask_strength: settings.mask_strength,
+        mask: crt::MaskType::from_u32(settings.mask_type),
+        mask_resolution: crt::MaskResolution::from_u32(settings.mask_resolution),
+        mask_compensation: settings.mask_compensation,
+        gamma: settings.gamma,
+        lanczos_size: settings.lanczos_size,
+        geometry: crt::CrtGeometry {
+            curvature_x: settings.curvature_x,
+            curvature_y: settings.curvature_y,
+            corner_radius: settings.corner_radius,
+            corner_smoothness: settings.corner_smoothness,
+            overscan: settings.overscan,
+            tilt_x: settings.tilt_x,
+            tilt_y: settings.tilt_y,
+        },
+    };
+    params.validate()?;
+
+    // An empty LUT means the bundled one is used
+    let lut = if identity_lut {
+        crt::CrtLut::Identity
+    } else if lut_data.is_empty() {
+        crt::CrtLut::Builtin
+    } else {
+        crt::CrtLut::Custom(lut::Lut3d::load(&lut_data)?)
+    };
+
     let exif_orientation = parse_exif_orientation(&data);
-    let img = image::load_from_memory(&data).unwrap();
+    let img = image::load_from_memory(&data).map_err(|e| format!("Failed to load image: {}", e))?;
     let (width, height) = exif_orientation_dimension(img.width(), img.height(), exif_orientation);
     let desired_aspect_ratio = width as f32 / height as f32;
 
@@ -361,6 +1463,29 @@ pub fn process_image_crt(
         downsample_image_nearest_neighbour(&img, &src_scale, true, exif_orientation)
     };
 
+    let adjustment = color_adjust::ColorAdjustment {
+        brightness,
+        contrast,
+        saturation,
+        hue,
+        temperature,
+        tint,
+        correction_strength,
+    };
+
+    let img = if adjustment.is_identity() {
+        img
+    } else {
+        color_adjust::adjust_gamma_image(&img, &adjustment)
+    };
+
+    // Render at the next integer scale, then resample down to the target
+    let scale = if target.is_set() {
+        target.integer_scale(img.width(), img.height())
+    } else {
+        scale
+    };
+
     let result = crt::crt(
         &img,
         &src_scale,
@@ -368,9 +1493,13 @@ pub fn process_image_crt(
         explicit_aspect_ratio,
         pixel_aspect_ratio,
         desired_aspect_ratio,
+        adjustment.correction_strength,
+        &params,
+        &lut,
     );
+    let result = fit_to_target(&result, &target);
 
     let mut buf = Vec::new();
     let _ = result.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png);
-    return general_purpose::STANDARD.encode(&buf);
+    Ok(general_purpose::STANDARD.encode(&buf))
 }
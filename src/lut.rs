@@ -0,0 +1,346 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::shader_support;
+
+use image::{Rgb, RgbaImage};
+use shader_support::*;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// Keeps a malformed file from allocating an absurd amount of memory
+const MAX_LUT_SIZE: usize = 256;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LutInterpolation {
+    Trilinear,
+    Tetrahedral,
+}
+
+impl LutInterpolation {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => LutInterpolation::Tetrahedral,
+            _ => LutInterpolation::Trilinear,
+        }
+    }
+}
+
+pub struct Lut3d {
+    size: usize,
+    // Red changes fastest, then green, then blue
+    data: Vec<Rgb<f32>>,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+}
+
+impl Lut3d {
+    // Accepts either a Hald CLUT or strip PNG, or a .cube text file
+    pub fn load(data: &[u8]) -> Result<Self, String> {
+        if data.starts_with(&PNG_SIGNATURE) {
+            let img = image::load_from_memory(data)
+                .map_err(|e| format!("Could not decode LUT image: {}", e))?
+                .to_rgba8();
+            if img.width() == img.height() {
+                Self::from_hald(&img)
+            } else {
+                Self::from_strip(&img)
+            }
+        } else {
+            let text =
+                std::str::from_utf8(data).map_err(|_| "LUT file is not valid text".to_string())?;
+            Self::parse_cube(text)
+        }
+    }
+
+    pub fn parse_cube(text: &str) -> Result<Self, String> {
+        let mut size = 0;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut data = Vec::new();
+
+        let parse_triplet = |line_no: usize, values: &[&str]| -> Result<[f32; 3], String> {
+            if values.len() != 3 {
+                return Err(format!("Line {}: expected 3 values", line_no));
+            }
+            let mut out = [0.0; 3];
+            for (o, v) in out.iter_mut().zip(values) {
+                *o = v
+                    .parse::<f32>()
+                    .map_err(|_| format!("Line {}: invalid number \"{}\"", line_no, v))?;
+            }
+            Ok(out)
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut tokens = line.split_whitespace();
+            let keyword = tokens.next().unwrap();
+            let values: Vec<&str> = tokens.collect();
+            match keyword {
+                "TITLE" => {}
+                "LUT_1D_SIZE" => return Err("1D LUTs are not supported".to_string()),
+                "LUT_3D_SIZE" => {
+                    size = values
+                        .first()
+                        .and_then(|v| v.parse::<usize>().ok())
+                        .filter(|&s| (2..=MAX_LUT_SIZE).contains(&s))
+                        .ok_or(format!("Line {}: invalid LUT_3D_SIZE", line_no))?;
+                    data.reserve(size * size * size);
+                }
+                "DOMAIN_MIN" => domain_min = parse_triplet(line_no, &values)?,
+                "DOMAIN_MAX" => domain_max = parse_triplet(line_no, &values)?,
+                // Older shorthand for the same domain on all three channels
+                "LUT_3D_INPUT_RANGE" => {
+                    let range = values
+                        .iter()
+                        .map(|v| v.parse::<f32>())
+                        .collect::<Result<Vec<f32>, _>>()
+                        .ok()
+                        .filter(|r| r.len() == 2)
+                        .ok_or(format!("Line {}: invalid LUT_3D_INPUT_RANGE", line_no))?;
+                    domain_min = [range[0]; 3];
+                    domain_max = [range[1]; 3];
+                }
+                // Skip other keywords, such as LUT_1D_INPUT_RANGE
+                _ if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {}
+                _ => {
+                    if size == 0 {
+                        return Err(format!("Line {}: data before LUT_3D_SIZE", line_no));
+                    }
+                    let mut row = vec![keyword];
+                    row.extend(values);
+                    data.push(Rgb(parse_triplet(line_no, &row)?));
+                }
+            }
+        }
+
+        if size == 0 {
+            return Err("Missing LUT_3D_SIZE".to_string());
+        }
+        if data.len() != size * size * size {
+            return Err(format!(
+                "Expected {} entries, found {}",
+                size * size * size,
+                data.len()
+            ));
+        }
+        if (0..3).any(|c| domain_max[c] <= domain_min[c]) {
+            return Err("DOMAIN_MAX must be greater than DOMAIN_MIN".to_string());
+        }
+
+        Ok(Lut3d {
+            size,
+            data,
+            domain_min,
+            domain_max,
+        })
+    }
+
+    // A level L Hald CLUT is an L^3 square image holding an L^2 sized cube
+    pub fn from_hald(img: &RgbaImage) -> Result<Self, String> {
+        let side = img.width();
+        if img.height() != side {
+            return Err("Hald CLUT must be square".to_string());
+        }
+        let level = (2..=16)
+            .find(|l| l * l * l == side)
+            .ok_or(format!("{}x{} is not a valid Hald CLUT size", side, side))?;
+        let size = (level * level) as usize;
+
+        let data = img.pixels().map(|p| rgba_u8_to_rgb_f32(*p)).collect();
+
+        Ok(Lut3d {
+            size,
+            data,
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+        })
+    }
+
+    // A strip holds one size x size slice per blue level, side by side
+    pub fn from_strip(img: &RgbaImage) -> Result<Self, String> {
+        let size = img.height() as usize;
+        if !(2..=MAX_LUT_SIZE).contains(&size) || img.width() as usize != size * size {
+            return Err(format!(
+                "{}x{} is not a valid LUT strip size, expected a width of height squared",
+                img.width(),
+                img.height()
+            ));
+        }
+
+        let mut data = Vec::with_capacity(size * size * size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    let p = img.get_pixel((r + b * size) as u32, g as u32);
+                    data.push(rgba_u8_to_rgb_f32(*p));
+                }
+            }
+        }
+
+        Ok(Lut3d {
+            size,
+            data,
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+        })
+    }
+
+    // Same cube with f applied to every entry
+    pub fn map(&self, f: impl Fn(Rgb<f32>) -> Rgb<f32>) -> Self {
+        Lut3d {
+            size: self.size,
+            data: self.data.iter().map(|&c| f(c)).collect(),
+            domain_min: self.domain_min,
+            domain_max: self.domain_max,
+        }
+    }
+
+    #[inline(always)]
+    fn entry(&self, r: usize, g: usize, b: usize) -> Rgb<f32> {
+        self.data[r + (g + b * self.size) * self.size]
+    }
+
+    // Input and output are in the gamma space the LUT was authored for
+    pub fn apply(&self, col: Rgb<f32>, interpolation: LutInterpolation) -> Rgb<f32> {
+        let max_index = (self.size - 1) as f32;
+
+        let mut index = [0usize; 3];
+        let mut frac = [0.0f32; 3];
+        for c in 0..3 {
+            let v = (col[c] - self.domain_min[c]) / (self.domain_max[c] - self.domain_min[c]);
+            let v = clamp(v, 0.0, 1.0) * max_index;
+            let i = (v.floor() as usize).min(self.size - 2);
+            index[c] = i;
+            frac[c] = v - i as f32;
+        }
+
+        let [r, g, b] = index;
+        let [dr, dg, db] = frac;
+
+        match interpolation {
+            LutInterpolation::Trilinear => {
+                let c00 = lerp_color(self.entry(r, g, b), self.entry(r + 1, g, b), dr);
+                let c01 = lerp_color(self.entry(r, g, b + 1), self.entry(r + 1, g, b + 1), dr);
+                let c10 = lerp_color(self.entry(r, g + 1, b), self.entry(r + 1, g + 1, b), dr);
+                let c11 = lerp_color(
+                    self.entry(r, g + 1, b + 1),
+                    self.entry(r + 1, g + 1, b + 1),
+                    dr,
+                );
+                let c0 = lerp_color(c00, c10, dg);
+                let c1 = lerp_color(c01, c11, dg);
+                lerp_color(c0, c1, db)
+            }
+            LutInterpolation::Tetrahedral => {
+                // Split the cube into six tetrahedra along its main diagonal
+                let c000 = self.entry(r, g, b);
+                let c111 = self.entry(r + 1, g + 1, b + 1);
+                let (c1, c2, w1, w2, w3) = if dr > dg {
+                    if dg > db {
+                        (
+                            self.entry(r + 1, g, b),
+                            self.entry(r + 1, g + 1, b),
+                            dr,
+                            dg,
+                            db,
+                        )
+                    } else if dr > db {
+                        (
+                            self.entry(r + 1, g, b),
+                            self.entry(r + 1, g, b + 1),
+                            dr,
+                            db,
+                            dg,
+                        )
+                    } else {
+                        (
+                            self.entry(r, g, b + 1),
+                            self.entry(r + 1, g, b + 1),
+                            db,
+                            dr,
+                            dg,
+                        )
+                    }
+                } else if db > dg {
+                    (
+                        self.entry(r, g, b + 1),
+                        self.entry(r, g + 1, b + 1),
+                        db,
+                        dg,
+                        dr,
+                    )
+                } else if db > dr {
+                    (
+                        self.entry(r, g + 1, b),
+                        self.entry(r, g + 1, b + 1),
+                        dg,
+                        db,
+                        dr,
+                    )
+                } else {
+                    (
+                        self.entry(r, g + 1, b),
+                        self.entry(r + 1, g + 1, b),
+                        dg,
+                        dr,
+                        db,
+                    )
+                };
+                c000.mult_f(1.0 - w1)
+                    .add(c1.mult_f(w1 - w2))
+                    .add(c2.mult_f(w2 - w3))
+                    .add(c111.mult_f(w3))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTITY: &str = "0 0 0\n1 0 0\n0 1 0\n1 1 0\n0 0 1\n1 0 1\n0 1 1\n1 1 1\n";
+
+    #[test]
+    fn input_range_sets_domain() {
+        let lut = Lut3d::parse_cube(&format!(
+            "LUT_3D_SIZE 2\nLUT_3D_INPUT_RANGE 0.5 2.0\n{}",
+            IDENTITY
+        ))
+        .unwrap();
+        assert_eq!(lut.domain_min, [0.5; 3]);
+        assert_eq!(lut.domain_max, [2.0; 3]);
+    }
+
+    #[test]
+    fn unknown_keywords_are_skipped() {
+        let lut = Lut3d::parse_cube(&format!(
+            "TITLE \"test\"\nLUT_3D_SIZE 2\nLUT_1D_INPUT_RANGE 0 1\nVENDOR_FLAG on\n{}",
+            IDENTITY
+        ))
+        .unwrap();
+        assert_eq!(lut.data.len(), 8);
+    }
+}
@@ -0,0 +1,394 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{colorimetry, gbc, scaling, shader_support};
+
+use gbc::DisplayProfile;
+use image::{Rgb, RgbaImage};
+use shader_support::*;
+use std::collections::HashMap;
+
+// Three equations per patch, for ten unknowns
+const MIN_PATCHES: usize = 4;
+
+// Patches smaller than this are too easily thrown off by misalignment
+const MIN_PATCH_PIXELS: u32 = 4;
+
+// Photos may be cropped slightly differently from the raw frame
+const ASPECT_TOLERANCE: f32 = 0.02;
+
+const MAX_ITERATIONS: usize = 20000;
+
+pub struct PatchError {
+    pub raw: Rgb<u8>,
+    // Linear sRGB
+    pub measured: Rgb<f32>,
+    pub fitted: Rgb<f32>,
+    pub pixels: u32,
+    pub delta_e: f32,
+}
+
+pub struct ProfileFit {
+    pub profile: DisplayProfile,
+    pub patches: Vec<PatchError>,
+    pub mean_delta_e: f32,
+    pub max_delta_e: f32,
+}
+
+struct Patch {
+    raw: Rgb<u8>,
+    // Raw colour as the colour correction sees it
+    input: Rgb<f32>,
+    measured: Rgb<f32>,
+    measured_lab: [f32; 3],
+    pixels: u32,
+}
+
+// Every raw colour becomes a patch, averaged over the pixels away from its edges
+fn collect_patches(pairs: &[(RgbaImage, RgbaImage)]) -> Result<Vec<Patch>, String> {
+    let mut sums: HashMap<[u8; 3], (Rgb<f32>, u32)> = HashMap::new();
+
+    for (i, (raw, photo)) in pairs.iter().enumerate() {
+        let raw_aspect = raw.width() as f32 / raw.height() as f32;
+        let photo_aspect = photo.width() as f32 / photo.height() as f32;
+        if (raw_aspect / photo_aspect - 1.0).abs() > ASPECT_TOLERANCE {
+            return Err(format!(
+                "Pair {}: photo is {}x{} but the raw frame is {}x{}, crop the photo to the screen",
+                i + 1,
+                photo.width(),
+                photo.height(),
+                raw.width(),
+                raw.height()
+            ));
+        }
+
+        let photo = FloatImage::from_fn(photo.width(), photo.height(), |x, y| {
+            rgba_u8_to_rgb_f32(*photo.get_pixel(x, y)).to_linear()
+        });
+        let photo = scaling::resample_area(
+            &photo,
+            0.0,
+            0.0,
+            photo.width() as f32,
+            photo.height() as f32,
+            raw.width(),
+            raw.height(),
+        );
+
+        let key = |x: u32, y: u32| {
+            let p = raw.get_pixel(x, y);
+            [p[0], p[1], p[2]]
+        };
+        for y in 1..raw.height().saturating_sub(1) {
+            for x in 1..raw.width().saturating_sub(1) {
+                let k = key(x, y);
+                let interior = (y - 1..=y + 1).all(|ny| (x - 1..=x + 1).all(|nx| key(nx, ny) == k));
+                if !interior {
+                    continue;
+                }
+                let entry = sums.entry(k).or_insert((Rgb([0.0; 3]), 0));
+                entry.0 = entry.0.add(*photo.get_pixel(x, y));
+                entry.1 += 1;
+            }
+        }
+    }
+
+    let mut patches: Vec<Patch> = sums
+        .into_iter()
+        .filter(|(_, (_, count))| *count >= MIN_PATCH_PIXELS)
+        .map(|(k, (sum, count))| {
+            let measured = sum.div_f(count as f32);
+            Patch {
+                raw: Rgb(k),
+                input: rgba_u8_to_rgb_f32(image::Rgba([k[0], k[1], k[2], 255])).to_linear(),
+                measured,
+                measured_lab: colorimetry::linear_srgb_to_lab(measured),
+                pixels: count,
+            }
+        })
+        .collect();
+    patches.sort_by_key(|p| p.raw.0);
+
+    if patches.len() < MIN_PATCHES {
+        return Err(format!(
+            "Found {} usable colour patches, at least {} are needed",
+            patches.len(),
+            MIN_PATCHES
+        ));
+    }
+    Ok(patches)
+}
+
+// Parameters are the 3x3 matrix with luminance folded in, then the gamma ratio
+fn params_to_profile(params: &[f32], template: &DisplayProfile) -> DisplayProfile {
+    DisplayProfile {
+        r: params[0],
+        gr: params[1],
+        br: params[2],
+        rg: params[3],
+        g: params[4],
+        bg: params[5],
+        rb: params[6],
+        gb: params[7],
+        b: params[8],
+        lum: 1.0,
+        gamma_offset: template.gamma * (params[9] - 1.0),
+        ..*template
+    }
+}
+
+// Moves the overall brightness out of the matrix, so the brightest channel of white is 1
+fn normalize_profile(prof: &DisplayProfile) -> DisplayProfile {
+    let scale = prof
+        .matrix()
+        .iter()
+        .map(|row| row[0] + row[1] + row[2])
+        .fold(f32::MIN, f32::max);
+    // Values above 1 would clip before the matrix, so lum stays in range
+    let lum = scale.clamp(f32::EPSILON, 1.0);
+    DisplayProfile {
+        r: prof.r / lum,
+        gr: prof.gr / lum,
+        br: prof.br / lum,
+        rg: prof.rg / lum,
+        g: prof.g / lum,
+        bg: prof.bg / lum,
+        rb: prof.rb / lum,
+        gb: prof.gb / lum,
+        b: prof.b / lum,
+        lum: prof.lum * lum,
+        ..*prof
+    }
+}
+
+fn mean_squared_error(patches: &[Patch], prof: &DisplayProfile) -> f32 {
+    let sum: f32 = patches
+        .iter()
+        .map(|p| {
+            let fitted = colorimetry::linear_srgb_to_lab(gbc::color_correct(p.input, prof));
+            colorimetry::delta_e_2000(p.measured_lab, fitted).powi(2)
+        })
+        .sum();
+    sum / patches.len() as f32
+}
+
+// Closed form least squares of the matrix in linear light, for a fixed gamma ratio
+fn linear_matrix(patches: &[Patch], ratio: f32) -> Option<Mat3> {
+    let mut xx = [[0.0; 3]; 3];
+    let mut yx = [[0.0; 3]; 3];
+    for p in patches {
+        let x = p.input.pow_f(ratio);
+        for r in 0..3 {
+            for c in 0..3 {
+                xx[r][c] += x[r] * x[c];
+                yx[r][c] += p.measured[r] * x[c];
+            }
+        }
+    }
+    Some(mat3_mul(&yx, &mat3_inverse(&xx)?))
+}
+
+fn nelder_mead(f: impl Fn(&[f32]) -> f32, start: &[f32], step: f32) -> Vec<f32> {
+    let n = start.len();
+    let mut simplex: Vec<Vec<f32>> = vec![start.to_vec()];
+    for i in 0..n {
+        let mut v = start.to_vec();
+        v[i] += step;
+        simplex.push(v);
+    }
+    let mut values: Vec<f32> = simplex.iter().map(|v| f(v)).collect();
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut order: Vec<usize> = (0..=n).collect();
+        order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+        simplex = order.iter().map(|&i| simplex[i].clone()).collect();
+        values = order.iter().map(|&i| values[i]).collect();
+
+        if values[n] - values[0] < 1e-7 {
+            break;
+        }
+
+        let centroid: Vec<f32> = (0..n)
+            .map(|d| simplex[..n].iter().map(|v| v[d]).sum::<f32>() / n as f32)
+            .collect();
+        let towards = |t: f32| -> Vec<f32> {
+            (0..n)
+                .map(|d| centroid[d] + t * (simplex[n][d] - centroid[d]))
+                .collect()
+        };
+
+        let reflected = towards(-1.0);
+        let reflected_value = f(&reflected);
+        if reflected_value < values[0] {
+            let expanded = towards(-2.0);
+            let expanded_value = f(&expanded);
+            if expanded_value < reflected_value {
+                simplex[n] = expanded;
+                values[n] = expanded_value;
+            } else {
+                simplex[n] = reflected;
+                values[n] = reflected_value;
+            }
+        } else if reflected_value < values[n - 1] {
+            simplex[n] = reflected;
+            values[n] = reflected_value;
+        } else {
+            let contracted = towards(0.5);
+            let contracted_value = f(&contracted);
+            if contracted_value < values[n] {
+                simplex[n] = contracted;
+                values[n] = contracted_value;
+            } else {
+                // Shrink everything towards the best point
+                for i in 1..=n {
+                    simplex[i] = (0..n)
+                        .map(|d| simplex[0][d] + 0.5 * (simplex[i][d] - simplex[0][d]))
+                        .collect();
+                    values[i] = f(&simplex[i]);
+                }
+            }
+        }
+    }
+
+    let best = (0..=n)
+        .min_by(|&a, &b| values[a].total_cmp(&values[b]))
+        .unwrap();
+    simplex[best].clone()
+}
+
+// Fits the colour response of a panel from raw frames and aligned photos of them on the hardware.
+// The colour mode supplies the target gamma and the subpixel layout.
+pub fn fit_display_profile(
+    pairs: &[(RgbaImage, RgbaImage)],
+    color_mode: u32,
+) -> Result<ProfileFit, String> {
+    if pairs.is_empty() {
+        return Err("No image pairs given".to_string());
+    }
+    let template = gbc::display_profile_preset(color_mode);
+    let patches = collect_patches(pairs)?;
+
+    let cost = |params: &[f32]| -> f32 {
+        if params[9] <= 0.05 {
+            return f32::MAX;
+        }
+        mean_squared_error(&patches, &params_to_profile(params, &template))
+    };
+
+    // Start from the best linear light fit over a range of gamma ratios
+    let start = (10..=30)
+        .filter_map(|i| {
+            let ratio = i as f32 * 0.05;
+            let m = linear_matrix(&patches, ratio)?;
+            let mut params: Vec<f32> = m.iter().flatten().copied().collect();
+            params.push(ratio);
+            Some(params)
+        })
+        .min_by(|a, b| cost(a).total_cmp(&cost(b)))
+        .ok_or("Patches do not cover enough different colours".to_string())?;
+
+    // Restarting lets a collapsed simplex open up again
+    let mut params = start;
+    for step in [0.1, 0.02, 0.005] {
+        params = nelder_mead(cost, &params, step);
+    }
+    let profile = normalize_profile(&params_to_profile(&params, &template));
+
+    let patches: Vec<PatchError> = patches
+        .iter()
+        .map(|p| {
+            let fitted = gbc::color_correct(p.input, &profile);
+            PatchError {
+                raw: p.raw,
+                measured: p.measured,
+                fitted,
+                pixels: p.pixels,
+                delta_e: colorimetry::delta_e_2000(
+                    p.measured_lab,
+                    colorimetry::linear_srgb_to_lab(fitted),
+                ),
+            }
+        })
+        .collect();
+    let mean_delta_e = patches.iter().map(|p| p.delta_e).sum::<f32>() / patches.len() as f32;
+    let max_delta_e = patches.iter().map(|p| p.delta_e).fold(0.0, f32::max);
+
+    Ok(ProfileFit {
+        profile,
+        patches,
+        mean_delta_e,
+        max_delta_e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_preset_from_its_own_output() {
+        let preset = gbc::display_profile_preset(0);
+
+        // A grid of 6x6 colour blocks, so each patch has interior pixels
+        let levels = [0u8, 128, 255];
+        let mut colors = Vec::new();
+        for r in levels {
+            for g in levels {
+                for b in levels {
+                    colors.push([r, g, b]);
+                }
+            }
+        }
+        let (block, columns) = (6, 9);
+        let rows = (colors.len() as u32).div_ceil(columns);
+        let raw = RgbaImage::from_fn(columns * block, rows * block, |x, y| {
+            let i = ((y / block) * columns + x / block) as usize;
+            let [r, g, b] = colors[i.min(colors.len() - 1)];
+            image::Rgba([r, g, b, 255])
+        });
+        let photo = RgbaImage::from_fn(raw.width(), raw.height(), |x, y| {
+            let c = gbc::color_correct(
+                rgba_u8_to_rgb_f32(*raw.get_pixel(x, y)).to_linear(),
+                &preset,
+            )
+            .to_gamma();
+            image::Rgba([
+                float_to_byte(c[0]),
+                float_to_byte(c[1]),
+                float_to_byte(c[2]),
+                255,
+            ])
+        });
+
+        let fit = fit_display_profile(&[(raw, photo)], 0).unwrap();
+        let prof = &fit.profile;
+        for (row, expected) in prof.matrix().iter().zip(preset.matrix()) {
+            for (v, e) in row.iter().zip(expected) {
+                assert!((v - e).abs() < 0.03, "{} != {}", v, e);
+            }
+        }
+        assert!((prof.lum - preset.lum).abs() < 0.03, "{}", prof.lum);
+        assert!(
+            (prof.gamma_offset - preset.gamma_offset).abs() < 0.1,
+            "{}",
+            prof.gamma_offset
+        );
+        assert!(fit.mean_delta_e < 1.0, "{}", fit.mean_delta_e);
+    }
+}
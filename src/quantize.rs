@@ -0,0 +1,187 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::shader_support;
+
+use image::Rgb;
+use shader_support::*;
+
+const CHANNEL_LEVELS: usize = 32;
+
+// Covers both rounding and bit replication when 5 bit values are expanded to 8 bit
+const SNAP_DISTANCE: f32 = 1.0 / 255.0;
+
+// Brightness of each 5 bit level on the GBC screen, from SameBoy's colour curves
+const GBC_CHANNEL_RESPONSE: [u8; CHANNEL_LEVELS] = [
+    0, 6, 12, 20, 28, 36, 45, 56, 66, 76, 88, 100, 113, 125, 137, 149, 161, 172, 182, 192, 202,
+    210, 218, 225, 232, 238, 243, 247, 250, 252, 254, 255,
+];
+
+// 4x4 Bayer, offset to sit in the middle of each step
+const BAYER_4X4: [[f32; 4]; 4] = [
+    [0.5, 8.5, 2.5, 10.5],
+    [12.5, 4.5, 14.5, 6.5],
+    [3.5, 11.5, 1.5, 9.5],
+    [15.5, 7.5, 13.5, 5.5],
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChannelResponse {
+    // Evenly spaced levels, as in RGB555 screenshots
+    Linear,
+    // Levels spaced like the GBC screen shows them
+    Gbc,
+}
+
+impl ChannelResponse {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => ChannelResponse::Gbc,
+            _ => ChannelResponse::Linear,
+        }
+    }
+
+    // Gamma space value of each level, in increasing order
+    fn levels(&self) -> [f32; CHANNEL_LEVELS] {
+        let mut levels = [0.0; CHANNEL_LEVELS];
+        for (i, level) in levels.iter_mut().enumerate() {
+            *level = match self {
+                ChannelResponse::Linear => i as f32 / (CHANNEL_LEVELS - 1) as f32,
+                ChannelResponse::Gbc => GBC_CHANNEL_RESPONSE[i] as f32 / 255.0,
+            };
+        }
+        levels
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum QuantizeDither {
+    None,
+    Ordered,
+    // Floyd-Steinberg
+    ErrorDiffusion,
+}
+
+impl QuantizeDither {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => QuantizeDither::Ordered,
+            2 => QuantizeDither::ErrorDiffusion,
+            _ => QuantizeDither::None,
+        }
+    }
+}
+
+// Input that is already on a level stays there, so 15 bit screenshots are left untouched
+#[inline(always)]
+fn snap_to_level(levels: &[f32; CHANNEL_LEVELS], v: f32) -> f32 {
+    let i = level_floor(levels, v);
+    let nearest = if v - levels[i] < levels[i + 1] - v {
+        levels[i]
+    } else {
+        levels[i + 1]
+    };
+    if (v - nearest).abs() < SNAP_DISTANCE {
+        nearest
+    } else {
+        v
+    }
+}
+
+// Index of the last level at or below v
+#[inline(always)]
+fn level_floor(levels: &[f32; CHANNEL_LEVELS], v: f32) -> usize {
+    levels
+        .iter()
+        .rposition(|&level| level <= v)
+        .unwrap_or(0)
+        .min(CHANNEL_LEVELS - 2)
+}
+
+// Picks between the two surrounding levels, rounding up when past the threshold
+#[inline(always)]
+fn quantize_channel(levels: &[f32; CHANNEL_LEVELS], v: f32, threshold: f32) -> usize {
+    let i = level_floor(levels, v);
+    let t = (v - levels[i]) / (levels[i + 1] - levels[i]);
+    if t >= threshold {
+        i + 1
+    } else {
+        i
+    }
+}
+
+// Reduces a linear image to the 5 bit per channel colours the hardware can output.
+// The result holds the raw hardware values, ready for colour correction.
+pub fn quantize_rgb555(
+    img: &FloatImage,
+    response: ChannelResponse,
+    dither: QuantizeDither,
+) -> FloatImage {
+    let levels = response.levels();
+    let (width, height) = (img.width(), img.height());
+
+    // Quantization happens in gamma space, where the hardware levels are defined
+    let mut src = FloatImage::from_fn(width, height, |x, y| {
+        let c = img.get_pixel(x, y).to_gamma();
+        Rgb([
+            snap_to_level(&levels, c[0]),
+            snap_to_level(&levels, c[1]),
+            snap_to_level(&levels, c[2]),
+        ])
+    });
+
+    let mut out = FloatImage::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let color = *src.get_pixel(x, y);
+            let threshold = match dither {
+                QuantizeDither::Ordered => BAYER_4X4[y as usize % 4][x as usize % 4] / 16.0,
+                _ => 0.5,
+            };
+
+            let mut raw = [0.0; 3];
+            let mut error = [0.0; 3];
+            for c in 0..3 {
+                let index = quantize_channel(&levels, clamp(color[c], 0.0, 1.0), threshold);
+                raw[c] = index as f32 / (CHANNEL_LEVELS - 1) as f32;
+                error[c] = color[c] - levels[index];
+            }
+            out.put_pixel(x, y, Rgb(raw).to_linear());
+
+            if dither == QuantizeDither::ErrorDiffusion {
+                let mut diffuse = |dx: i32, dy: i32, weight: f32| {
+                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                    if nx < 0 || nx >= width as i32 || ny >= height as i32 {
+                        return;
+                    }
+                    let p = src.get_pixel_mut(nx as u32, ny as u32);
+                    for c in 0..3 {
+                        p[c] += error[c] * weight;
+                    }
+                };
+                diffuse(1, 0, 7.0 / 16.0);
+                diffuse(-1, 1, 3.0 / 16.0);
+                diffuse(0, 1, 5.0 / 16.0);
+                diffuse(1, 1, 1.0 / 16.0);
+            }
+        }
+    }
+
+    out
+}
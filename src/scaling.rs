@@ -18,11 +18,13 @@
 */
 
 use crate::{
-    shader_support::{self, lerp_color, rgba_u8_to_rgb_f32, FloatImage, ShaderSupport},
+    shader_support::{
+        self, float_to_byte, lerp_color, rgba_u8_to_rgb_f32, FloatImage, ShaderSupport,
+    },
     utils,
 };
 
-use image::{GenericImageView, Rgb, RgbaImage};
+use image::{GenericImageView, Rgb, Rgba, RgbaImage};
 use utils::set_panic_hook;
 use wasm_bindgen::prelude::*;
 
@@ -349,3 +351,172 @@ pub fn downsample_image_bilinear(
 
     src
 }
+
+pub fn resample_area(
+    src: &FloatImage,
+    crop_x: f32,
+    crop_y: f32,
+    crop_width: f32,
+    crop_height: f32,
+    dst_width: u32,
+    dst_height: u32,
+) -> FloatImage {
+    // Box filter over the crop rectangle, weighting each source pixel by how much of it is covered.
+    // Input is expected to be linear, so averaging here is physically correct.
+    let (src_width, src_height) = (src.width() as i32, src.height() as i32);
+    let step_x = crop_width / dst_width as f32;
+    let step_y = crop_height / dst_height as f32;
+
+    FloatImage::from_fn(dst_width, dst_height, |x, y| {
+        let x0 = crop_x + x as f32 * step_x;
+        let x1 = x0 + step_x;
+        let y0 = crop_y + y as f32 * step_y;
+        let y1 = y0 + step_y;
+
+        let mut sum = Rgb::<f32>([0.0, 0.0, 0.0]);
+        let mut total = 0.0;
+        for sy in (y0.floor() as i32)..(y1.ceil() as i32) {
+            let wy = (y1.min(sy as f32 + 1.0) - y0.max(sy as f32)).max(0.0);
+            let sy = sy.clamp(0, src_height - 1) as u32;
+            for sx in (x0.floor() as i32)..(x1.ceil() as i32) {
+                let wx = (x1.min(sx as f32 + 1.0) - x0.max(sx as f32)).max(0.0);
+                let sx = sx.clamp(0, src_width - 1) as u32;
+                let w = wx * wy;
+                sum = sum.add(src.get_pixel(sx, sy).mult_f(w));
+                total += w;
+            }
+        }
+        if total > 0.0 {
+            sum.div_f(total)
+        } else {
+            sum
+        }
+    })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFit {
+    // Keep the aspect ratio, and letterbox or pillarbox onto the canvas
+    Contain,
+    // Fill the whole canvas, ignoring the aspect ratio
+    Stretch,
+}
+
+impl OutputFit {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => OutputFit::Stretch,
+            _ => OutputFit::Contain,
+        }
+    }
+}
+
+// Largest target size in either dimension, which keeps the canvas allocation bounded
+pub const MAX_TARGET_SIZE: u32 = 4096;
+
+pub struct OutputTarget {
+    // Zero leaves the dimension to follow the aspect ratio of the image
+    pub width: u32,
+    pub height: u32,
+    pub fit: OutputFit,
+    pub canvas: Rgba<u8>,
+}
+
+impl OutputTarget {
+    pub fn is_set(&self) -> bool {
+        self.width > 0 || self.height > 0
+    }
+
+    // Size the image itself should be rendered at to fit the target
+    pub fn content_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let aspect_ratio = width as f32 / height as f32;
+        // The derived dimension is capped too, for images with extreme aspect ratios
+        let derive = |v: f32| (v.round() as u32).clamp(1, MAX_TARGET_SIZE);
+        let from_width = |w: u32| (w, derive(w as f32 / aspect_ratio));
+        let from_height = |h: u32| (derive(h as f32 * aspect_ratio), h);
+        match (self.width, self.height) {
+            (0, 0) => (width, height),
+            (w, 0) => from_width(w),
+            (0, h) => from_height(h),
+            (w, h) => match self.fit {
+                OutputFit::Stretch => (w, h),
+                OutputFit::Contain => {
+                    if w as f32 / h as f32 > aspect_ratio {
+                        from_height(h)
+                    } else {
+                        from_width(w)
+                    }
+                }
+            },
+        }
+    }
+
+    // Smallest integer scale that renders at least as large as the target
+    pub fn integer_scale(&self, width: u32, height: u32) -> u32 {
+        let (content_width, content_height) = self.content_size(width, height);
+        content_width
+            .div_ceil(width)
+            .max(content_height.div_ceil(height))
+            .clamp(1, MAX_INTEGER_SCALE)
+    }
+}
+
+// Keeps memory use in check when the target is much larger than the source
+const MAX_INTEGER_SCALE: u32 = 16;
+
+// Resizes with a box filter in linear light, which also works as a sharp filter when upscaling
+pub fn resize_image(img: &RgbaImage, width: u32, height: u32) -> RgbaImage {
+    if img.width() == width && img.height() == height {
+        return img.clone();
+    }
+    let src = FloatImage::from_fn(img.width(), img.height(), |x, y| {
+        rgba_u8_to_rgb_f32(*img.get_pixel(x, y)).to_linear()
+    });
+    let resized = resample_area(
+        &src,
+        0.0,
+        0.0,
+        img.width() as f32,
+        img.height() as f32,
+        width,
+        height,
+    );
+    RgbaImage::from_fn(width, height, |x, y| {
+        let c = resized.get_pixel(x, y).to_gamma();
+        Rgba([
+            float_to_byte(c[0]),
+            float_to_byte(c[1]),
+            float_to_byte(c[2]),
+            255,
+        ])
+    })
+}
+
+// Resizes the rendered image to the target, and centres it on the canvas
+pub fn fit_to_target(img: &RgbaImage, target: &OutputTarget) -> RgbaImage {
+    if !target.is_set() {
+        return img.clone();
+    }
+    let (content_width, content_height) = target.content_size(img.width(), img.height());
+    let content = resize_image(img, content_width, content_height);
+
+    let canvas_width = target.width.max(content_width);
+    let canvas_height = target.height.max(content_height);
+    if canvas_width == content_width && canvas_height == content_height {
+        return content;
+    }
+
+    let offset_x = (canvas_width - content_width) / 2;
+    let offset_y = (canvas_height - content_height) / 2;
+    RgbaImage::from_fn(canvas_width, canvas_height, |x, y| {
+        if x < offset_x
+            || y < offset_y
+            || x >= offset_x + content_width
+            || y >= offset_y + content_height
+        {
+            target.canvas
+        } else {
+            *content.get_pixel(x - offset_x, y - offset_y)
+        }
+    })
+}
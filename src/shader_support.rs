@@ -213,3 +213,86 @@ pub fn rgba_u8_to_rgb_f32(rgb: Rgba<u8>) -> Rgb<f32> {
         rgb[2] as f32 / 255.0,
     ])
 }
+
+pub type Mat3 = [[f32; 3]; 3];
+
+#[inline(always)]
+pub fn mat3_mul_color(m: &Mat3, c: Rgb<f32>) -> Rgb<f32> {
+    Rgb::<f32>([
+        m[0][0] * c[0] + m[0][1] * c[1] + m[0][2] * c[2],
+        m[1][0] * c[0] + m[1][1] * c[1] + m[1][2] * c[2],
+        m[2][0] * c[0] + m[2][1] * c[1] + m[2][2] * c[2],
+    ])
+}
+
+pub fn mat3_mul(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut out = [[0.0; 3]; 3];
+    for (r, row) in out.iter_mut().enumerate() {
+        for (c, v) in row.iter_mut().enumerate() {
+            *v = a[r][0] * b[0][c] + a[r][1] * b[1][c] + a[r][2] * b[2][c];
+        }
+    }
+    out
+}
+
+// Returns None for singular matrices
+pub fn mat3_inverse(m: &Mat3) -> Option<Mat3> {
+    let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| -> f32 {
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let c00 = cofactor(1, 2, 1, 2);
+    let c01 = -cofactor(1, 2, 0, 2);
+    let c02 = cofactor(1, 2, 0, 1);
+    let det = m[0][0] * c00 + m[0][1] * c01 + m[0][2] * c02;
+    if det.abs() < 1e-8 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    Some([
+        [
+            c00 * inv_det,
+            -cofactor(0, 2, 1, 2) * inv_det,
+            cofactor(0, 1, 1, 2) * inv_det,
+        ],
+        [
+            c01 * inv_det,
+            cofactor(0, 2, 0, 2) * inv_det,
+            -cofactor(0, 1, 0, 2) * inv_det,
+        ],
+        [
+            c02 * inv_det,
+            -cofactor(0, 2, 0, 1) * inv_det,
+            cofactor(0, 1, 0, 1) * inv_det,
+        ],
+    ])
+}
+
+// Approximate blackbody colour in gamma space, from Tanner Helland's fit
+fn blackbody_gamma(kelvin: f32) -> Rgb<f32> {
+    let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (t - 60.0).powf(-0.13320476)
+    };
+    let g = if t <= 66.0 {
+        99.4708 * t.ln() - 161.11957
+    } else {
+        288.12216 * (t - 60.0).powf(-0.07551485)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (t - 10.0).ln() - 305.0448
+    };
+    Rgb::<f32>([r, g, b]).div_f(255.0).clamp01()
+}
+
+// Linear light colour of a light source, normalized so that 6500K is white
+pub fn color_temperature_rgb(kelvin: f32) -> Rgb<f32> {
+    blackbody_gamma(kelvin)
+        .to_linear()
+        .div(blackbody_gamma(6500.0).to_linear())
+}
@@ -0,0 +1,306 @@
+/*
+    DisplayBoy
+
+    Copyright (C) 2024 coding-fish-1989
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{gb, shader_support};
+
+use gb::ShadeImage;
+use image::{Luma, Rgb, Rgba, RgbaImage};
+use shader_support::*;
+
+// Chance of a pixel being dead or stuck at full intensity
+const DEFECT_RATE: f32 = 0.002;
+const MAX_VERTICAL_LINES: f32 = 8.0;
+const MAX_SCRATCHES: f32 = 40.0;
+const MAX_SCUFFS: f32 = 150.0;
+const MAX_DUST: f32 = 60.0;
+const SCUFF_CLUSTER: u32 = 15;
+
+// Old lenses turn yellow and scatter light over the whole screen
+const HAZE_TINT: Rgb<f32> = Rgb([1.0, 0.93, 0.72]);
+const HAZE_VEIL: Rgb<f32> = Rgb([0.06, 0.055, 0.045]);
+// Scratches catch the light, dust blocks it
+const SCRATCH_LIGHT: Rgb<f32> = Rgb([1.0, 1.0, 0.97]);
+const DUST_COLOR: Rgb<f32> = Rgb([0.03, 0.03, 0.025]);
+
+// Each effect has its own intensity, 0.0 leaves the screen as new
+pub struct ScreenWear {
+    pub seed: u32,
+    pub scratches: f32,
+    pub dust: f32,
+    pub dead_pixels: f32,
+    pub haze: f32,
+    // Columns lost to a failing ribbon cable, only on the DMG
+    pub vertical_lines: f32,
+}
+
+impl ScreenWear {
+    fn has_lens_wear(&self) -> bool {
+        self.scratches > 0.0 || self.dust > 0.0 || self.haze > 0.0
+    }
+}
+
+// Effects draw from their own streams, so changing one leaves the others in place
+const STREAM_PIXELS: u64 = 1;
+const STREAM_LINES: u64 = 2;
+const STREAM_SCRATCHES: u64 = 3;
+const STREAM_SCUFFS: u64 = 4;
+const STREAM_DUST: u64 = 5;
+
+// SplitMix64, small and the same on every platform
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u32, stream: u64) -> Self {
+        Rng((((seed as u64) << 32) ^ stream).wrapping_mul(0x9E3779B97F4A7C15))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn range(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.next_f32()
+    }
+}
+
+// Per pixel random value, stable no matter how many pixels are looked at.
+// The seed is hashed on its own first, so no seed lines up with a shifted position.
+fn pixel_random(seed: u32, x: u32, y: u32) -> f32 {
+    let base = Rng::new(seed, STREAM_PIXELS).next_u64();
+    Rng(base ^ (x as u64 | (y as u64) << 32)).next_f32()
+}
+
+// Dead pixels let no light through and stuck pixels let all of it through.
+// Colour screens fail one subpixel at a time, the DMG has no subpixels and fails whole pixels.
+enum Defect {
+    Dead,
+    Stuck(usize),
+}
+
+fn pixel_defect(wear: &ScreenWear, x: u32, y: u32) -> Option<Defect> {
+    let rate = wear.dead_pixels.clamp(0.0, 1.0) * DEFECT_RATE;
+    let r = pixel_random(wear.seed, x, y);
+    if r >= rate {
+        return None;
+    }
+    // Half are dead, the other half are stuck on one subpixel
+    if r < rate * 0.5 {
+        Some(Defect::Dead)
+    } else {
+        Some(Defect::Stuck((r / rate * 6.0) as usize % 3))
+    }
+}
+
+// Dead pixels are the darkest shade, stuck pixels the lightest.
+// Columns lost to the ribbon cable are never driven, so they stay the lightest shade.
+pub fn wear_shades(shades: &ShadeImage, wear: &ScreenWear) -> ShadeImage {
+    let mut rng = Rng::new(wear.seed, STREAM_LINES);
+    let line_count = (wear.vertical_lines.clamp(0.0, 1.0) * MAX_VERTICAL_LINES).round() as u32;
+    let mut dead_columns = Vec::new();
+    for _ in 0..line_count {
+        let x = (rng.next_f32() * shades.width() as f32) as u32;
+        // Neighbouring lines often fail together
+        let width = if rng.next_f32() < 0.3 { 2 } else { 1 };
+        dead_columns.extend(x..x + width);
+    }
+
+    ShadeImage::from_fn(shades.width(), shades.height(), |x, y| {
+        if dead_columns.contains(&x) {
+            return Luma([0]);
+        }
+        match pixel_defect(wear, x, y) {
+            Some(Defect::Dead) => Luma([3]),
+            Some(Defect::Stuck(_)) => Luma([0]),
+            None => *shades.get_pixel(x, y),
+        }
+    })
+}
+
+// Takes raw linear colours at the panel resolution
+pub fn wear_pixels(img: &FloatImage, wear: &ScreenWear) -> FloatImage {
+    FloatImage::from_fn(img.width(), img.height(), |x, y| {
+        match pixel_defect(wear, x, y) {
+            Some(Defect::Dead) => Rgb([0.0, 0.0, 0.0]),
+            Some(Defect::Stuck(channel)) => {
+                let mut c = *img.get_pixel(x, y);
+                c[channel] = 1.0;
+                c
+            }
+            None => *img.get_pixel(x, y),
+        }
+    })
+}
+
+// Distance from p to the segment a to b
+fn segment_distance(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length_squared = (dx * dx + dy * dy).max(f32::EPSILON);
+    let t = (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length_squared).clamp(0.0, 1.0);
+    let (cx, cy) = (a.0 + t * dx - p.0, a.1 + t * dy - p.1);
+    (cx * cx + cy * cy).sqrt()
+}
+
+// Draws a feature over its bounding box, blend gives the coverage at each pixel
+fn draw_feature(
+    img: &mut FloatImage,
+    bounds: (f32, f32, f32, f32),
+    color: Rgb<f32>,
+    additive: bool,
+    blend: impl Fn(f32, f32) -> f32,
+) {
+    let (x0, y0, x1, y1) = bounds;
+    let (width, height) = (img.width(), img.height());
+    let clamp_x = |v: f32| (v.max(0.0) as u32).min(width);
+    let clamp_y = |v: f32| (v.max(0.0) as u32).min(height);
+    for y in clamp_y(y0)..clamp_y(y1 + 1.0) {
+        for x in clamp_x(x0)..clamp_x(x1 + 1.0) {
+            let a = blend(x as f32 + 0.5, y as f32 + 0.5);
+            if a <= 0.0 {
+                continue;
+            }
+            let c = *img.get_pixel(x, y);
+            let c = if additive {
+                c.add(color.mult_f(a))
+            } else {
+                lerp_color(c, color, a.min(1.0))
+            };
+            img.put_pixel(x, y, c);
+        }
+    }
+}
+
+// Light line from a to b, line_width in output pixels
+fn draw_scratch(
+    img: &mut FloatImage,
+    a: (f32, f32),
+    b: (f32, f32),
+    strength: f32,
+    line_width: f32,
+) {
+    let bounds = (
+        a.0.min(b.0) - line_width,
+        a.1.min(b.1) - line_width,
+        a.0.max(b.0) + line_width,
+        a.1.max(b.1) + line_width,
+    );
+    draw_feature(img, bounds, SCRATCH_LIGHT, true, |x, y| {
+        let d = segment_distance((x, y), a, b);
+        strength * (1.0 - d / line_width).max(0.0)
+    });
+}
+
+// Point at a random angle and distance, with the length relative to the screen size
+fn scratch_end(rng: &mut Rng, a: (f32, f32), length: (f32, f32), size: f32) -> (f32, f32) {
+    let angle = rng.next_f32() * std::f32::consts::TAU;
+    let len = rng.range(length.0, length.1) * size;
+    (a.0 + angle.cos() * len, a.1 + angle.sin() * len)
+}
+
+// Lens wear over the rendered screen, in output pixels
+pub fn wear_lens(img: &RgbaImage, wear: &ScreenWear) -> RgbaImage {
+    if !wear.has_lens_wear() {
+        return img.clone();
+    }
+
+    let haze = wear.haze.clamp(0.0, 1.0);
+    let tint = lerp_color(Rgb([1.0, 1.0, 1.0]), HAZE_TINT, haze);
+    let mut out = FloatImage::from_fn(img.width(), img.height(), |x, y| {
+        let c = rgba_u8_to_rgb_f32(*img.get_pixel(x, y)).to_linear();
+        c.mult(tint)
+            .mult_f(1.0 - 0.1 * haze)
+            .add(HAZE_VEIL.mult_f(haze))
+    });
+
+    let (width, height) = (out.width() as f32, out.height() as f32);
+    let size = width.min(height);
+
+    let scratches = wear.scratches.clamp(0.0, 1.0);
+    let mut rng = Rng::new(wear.seed, STREAM_SCRATCHES);
+    for _ in 0..(scratches * MAX_SCRATCHES).round() as u32 {
+        let a = (rng.next_f32() * width, rng.next_f32() * height);
+        let b = scratch_end(&mut rng, a, (0.05, 0.4), size);
+        let strength = rng.range(0.05, 0.2);
+        draw_scratch(&mut out, a, b, strength, (size * 0.002).max(1.0));
+    }
+
+    // Scuffs are clusters of fine, faint scratches
+    let mut rng = Rng::new(wear.seed, STREAM_SCUFFS);
+    for _ in 0..(scratches * MAX_SCUFFS / SCUFF_CLUSTER as f32).round() as u32 {
+        let (cx, cy) = (rng.next_f32() * width, rng.next_f32() * height);
+        for _ in 0..SCUFF_CLUSTER {
+            let a = (
+                cx + rng.range(-0.05, 0.05) * size,
+                cy + rng.range(-0.05, 0.05) * size,
+            );
+            let b = scratch_end(&mut rng, a, (0.01, 0.04), size);
+            let strength = rng.range(0.02, 0.06);
+            draw_scratch(&mut out, a, b, strength, (size * 0.0015).max(1.0));
+        }
+    }
+
+    let mut rng = Rng::new(wear.seed, STREAM_DUST);
+    let dust_count = (wear.dust.clamp(0.0, 1.0) * MAX_DUST).round() as u32;
+    for _ in 0..dust_count {
+        let (x, y) = (rng.next_f32() * width, rng.next_f32() * height);
+        let radius = (rng.range(0.002, 0.008) * size).max(0.75);
+        let opacity = rng.range(0.3, 0.8);
+        let bounds = (x - radius, y - radius, x + radius, y + radius);
+        // Soft edges, as the speck sits on the lens away from the panel
+        draw_feature(&mut out, bounds, DUST_COLOR, false, |px, py| {
+            let d = ((px - x).powi(2) + (py - y).powi(2)).sqrt() / radius;
+            opacity * (1.0 - d * d).max(0.0)
+        });
+    }
+
+    RgbaImage::from_fn(out.width(), out.height(), |x, y| {
+        let c = out.get_pixel(x, y).clamp01().to_gamma();
+        Rgba([
+            float_to_byte(c[0]),
+            float_to_byte(c[1]),
+            float_to_byte(c[2]),
+            255,
+        ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeds_do_not_repeat_shifted() {
+        let row = |seed: u32, y: u32| {
+            (0..160)
+                .map(|x| pixel_random(seed, x, y))
+                .collect::<Vec<_>>()
+        };
+        // Seeds 256 apart used to give the same rows, one row apart
+        assert_ne!(row(257, 0), row(1, 1));
+        assert_ne!(row(1, 0), row(2, 0));
+    }
+}
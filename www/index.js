@@ -10,7 +10,41 @@ let invert = document.getElementById('invert');
 let gbFgColor = document.getElementById('gbCustomFg');
 let gbFgOpacity = document.getElementById('gbCustomFgOpacity');
 let gbBgColor = document.getElementById('gbCustomBg');
+let gbPaletteInput = document.getElementById('gbPaletteInput');
+let gbColorizeGameTitle = document.getElementById('gbColorizeGameTitle');
+let gbColorizeNintendo = document.getElementById('gbColorizeNintendo');
+let gbColorizeMaskInput = document.getElementById('gbColorizeMaskInput');
 let scaling = document.getElementById('scaling');
+let gridStrength = document.getElementById('gridStrength');
+let subpixelFill = document.getElementById('subpixelFill');
+let lutInput = document.getElementById('lutInput');
+let targetWidth = document.getElementById('targetWidth');
+let targetHeight = document.getElementById('targetHeight');
+let canvasColor = document.getElementById('canvasColor');
+let ambientLight = document.getElementById('ambientLight');
+let ambientBrightness = document.getElementById('ambientBrightness');
+let ambientTemperature = document.getElementById('ambientTemperature');
+let glare = document.getElementById('glare');
+let frontLight = document.getElementById('frontLight');
+let viewingAngle = document.getElementById('viewingAngle');
+let viewVertical = document.getElementById('viewVertical');
+let viewHorizontal = document.getElementById('viewHorizontal');
+let tiltGradient = document.getElementById('tiltGradient');
+let wear = document.getElementById('wear');
+let wearSeed = document.getElementById('wearSeed');
+let scratches = document.getElementById('scratches');
+let dust = document.getElementById('dust');
+let deadPixels = document.getElementById('deadPixels');
+let haze = document.getElementById('haze');
+let verticalLines = document.getElementById('verticalLines');
+let colorAdjustment = document.getElementById('colorAdjustment');
+let colorBrightness = document.getElementById('colorBrightness');
+let colorContrast = document.getElementById('colorContrast');
+let saturation = document.getElementById('saturation');
+let hue = document.getElementById('hue');
+let whiteBalance = document.getElementById('whiteBalance');
+let tint = document.getElementById('tint');
+let correctionStrength = document.getElementById('correctionStrength');
 let imageHeightCap = document.getElementById('imageHeightCap');
 let imageDownsampleMethod = document.getElementById('imageDownsampleMethod');
 let convertButton = document.getElementById('convertButton');
@@ -33,6 +67,15 @@ function remap(value, low1, high1, low2, high2) {
     return lerp(low2, high2, inv_lerp(low1, high1, value));
 }
 
+function base64ToBytes(base64) {
+    const byteCharacters = atob(base64);
+    const byteArray = new Uint8Array(byteCharacters.length);
+    for (let i = 0; i < byteCharacters.length; i++) {
+        byteArray[i] = byteCharacters.charCodeAt(i);
+    }
+    return byteArray;
+}
+
 function pngBytesToFile(base64, fileName) {
     const byteCharacters = atob(base64);
     const byteArray = new Uint8Array(byteCharacters.length);
@@ -70,11 +113,115 @@ fileInput.onchange = function () {
     }
 }
 
+// Per-shade opacity from the last calibration, empty for the defaults
+let gbShadeOpacity = new Float32Array(0);
+
+gbPaletteInput.onchange = async function () {
+    let gbPaletteInfo = document.getElementById('gbPaletteInfo');
+    gbPaletteInfo.innerText = "";
+    if (gbPaletteInput.files.length == 0) {
+        return;
+    }
+    try {
+        let data = new Uint8Array(await gbPaletteInput.files[0].arrayBuffer());
+        let estimate = wasm.estimateGbPalette(data);
+        gbFgColor.value = estimate.fgColor;
+        gbBgColor.value = estimate.bgColor;
+        gbFgOpacity.value = estimate.fgAlpha;
+        gbShadeOpacity = estimate.shadeOpacity;
+        updateForm();
+        let estimatedShades = Array.from(estimate.estimatedShades);
+        gbPaletteInfo.innerText = "Shade opacity: " + Array.from(gbShadeOpacity)
+            .map((v, shade) => v.toFixed(2) + (estimatedShades.includes(shade) ? "" : " (default)")).join(", ") +
+            ", confidence: " + (estimate.confidence * 100).toFixed(0) + "%";
+    } catch (e) {
+        gbPaletteInfo.innerText = e;
+    }
+};
+
+// Margin and filter size come from the preset, the form tunes the rest
+let crtSettings = wasm.crtPreset(0);
+let scanlineWeight = document.getElementById('scanlineWeight');
+let maskStrength = document.getElementById('maskStrength');
+let maskCompensation = document.getElementById('maskCompensation');
+let crtGamma = document.getElementById('crtGamma');
+let crtLum = document.getElementById('crtLum');
+let crtCurvature = document.getElementById('crtCurvature');
+let crtLutInput = document.getElementById('crtLutInput');
+let crtIdentityLut = document.getElementById('crtIdentityLut');
+
+document.querySelectorAll('input[name="crtPreset"]').forEach(function (input) {
+    input.addEventListener('change', function () {
+        crtSettings = wasm.crtPreset(parseInt(input.value));
+        scanlineWeight.value = Math.round(crtSettings.scanlineWeight * 100);
+        maskStrength.value = Math.round(crtSettings.maskStrength * 100);
+        maskCompensation.value = Math.round(crtSettings.maskCompensation * 100);
+        document.querySelector('input[name="maskType"][value="' + crtSettings.maskType + '"]').checked = true;
+        document.querySelector('input[name="maskResolution"][value="' + crtSettings.maskResolution + '"]').checked = true;
+        crtGamma.value = Math.round(crtSettings.gamma * 10);
+        crtLum.value = Math.round(crtSettings.lum * 100);
+        updateForm();
+    });
+});
+
+// CRT sits between the original and the additional colour profiles
+function selectedGbcColorMode() {
+    let colorModeValue = parseInt(document.querySelector('input[name="colorMode"]:checked').value);
+    // NDS Lite backlight levels 1 to 3 are profiles 9 to 11, level 4 is the NDS Lite profile itself
+    if (colorModeValue == 10) {
+        let backlight = parseInt(document.querySelector('input[name="ndsLiteBacklight"]:checked').value);
+        if (backlight < 4) {
+            return backlight + 8;
+        }
+    }
+    return colorModeValue <= 7 ? colorModeValue - 4 : colorModeValue - 5;
+}
+
+const measurementFields = {
+    redX: 'measuredRedX', redY: 'measuredRedY',
+    greenX: 'measuredGreenX', greenY: 'measuredGreenY',
+    blueX: 'measuredBlueX', blueY: 'measuredBlueY',
+    whiteX: 'measuredWhiteX', whiteY: 'measuredWhiteY',
+    peakLuminance: 'measuredPeakLuminance', gamma: 'measuredGamma',
+};
+
+document.getElementById('loadMeasurementButton').onclick = function () {
+    let profileError = document.getElementById('profileError');
+    profileError.innerText = "";
+    try {
+        let measurement = wasm.measureDisplayProfile(selectedGbcColorMode());
+        for (const [key, id] of Object.entries(measurementFields)) {
+            document.getElementById(id).value = measurement[key].toFixed(4);
+        }
+    } catch (e) {
+        profileError.innerText = e;
+    }
+};
+
+document.getElementById('deriveProfileButton').onclick = function () {
+    let profileError = document.getElementById('profileError');
+    let output = document.getElementById('profileCoefficients');
+    profileError.innerText = "";
+    output.innerText = "";
+    let v = Object.values(measurementFields).map(id => parseFloat(document.getElementById(id).value));
+    try {
+        let p = wasm.deriveDisplayProfile(selectedGbcColorMode(), ...v, document.getElementById('adaptWhite').checked);
+        let f = x => x.toFixed(4);
+        output.innerText =
+            "r: " + f(p.r) + ", gr: " + f(p.gr) + ", br: " + f(p.br) + "\n" +
+            "rg: " + f(p.rg) + ", g: " + f(p.g) + ", bg: " + f(p.bg) + "\n" +
+            "rb: " + f(p.rb) + ", gb: " + f(p.gb) + ", b: " + f(p.b) + "\n" +
+            "lum: " + f(p.lum) + ", gamma: " + f(p.gamma) + ", gamma_offset: " + f(p.gammaOffset);
+    } catch (e) {
+        profileError.innerText = e;
+    }
+};
+
 convertButton.onclick = function () {
     let files = fileInput.files;
     let fileName = files[0].name.replace(/\.[^/.]+$/, "");
     var fileReader = new FileReader();
-    fileReader.onload = function () {
+    fileReader.onload = async function () {
         let data = new Uint8Array(fileReader.result)
 
         var colorModeValue = parseInt(document.querySelector('input[name="colorMode"]:checked').value);
@@ -113,34 +260,147 @@ convertButton.onclick = function () {
 
         var requestBilinear = imageDownsampleMethodValue == "bilinear";
 
+        // Empty size fields leave that dimension to follow the aspect ratio
+        let targetWidthVal = parseInt(targetWidth.value) || 0;
+        let targetHeightVal = parseInt(targetHeight.value) || 0;
+        let outputFitVal = parseInt(document.querySelector('input[name="outputFit"]:checked').value);
+        let canvasColorVal = canvasColor.value;
+
+        // Neutral values when the adjustments are switched off
+        let adjustmentVals = [1.0, 1.0, 1.0, 0.0, 6500.0, 0.0, 1.0];
+        if (colorAdjustment.checked) {
+            adjustmentVals = [
+                parseInt(colorBrightness.value) / 100.0,
+                parseInt(colorContrast.value) / 100.0,
+                parseInt(saturation.value) / 100.0,
+                parseFloat(hue.value),
+                parseFloat(whiteBalance.value),
+                parseInt(tint.value) / 100.0,
+                parseInt(correctionStrength.value) / 100.0,
+            ];
+        }
+
+        // Seed, scratches, dust, dead pixels and haze, all off when wear is switched off
+        let wearVals = [0, 0.0, 0.0, 0.0, 0.0];
+        let verticalLinesVal = 0.0;
+        if (wear.checked) {
+            wearVals = [
+                parseInt(wearSeed.value) || 0,
+                parseInt(scratches.value) / 100.0,
+                parseInt(dust.value) / 100.0,
+                parseInt(deadPixels.value) / 100.0,
+                parseInt(haze.value) / 100.0,
+            ];
+            verticalLinesVal = parseInt(verticalLines.value) / 100.0;
+        }
+
         var imgData = null;
-        if (colorModeValue < 3) {
-            imgData = wasm.processImageGb(colorModeValue, ditherValue, brightnessValue, contrastValue, invertValue, edgeEnhancementLevelValue, imageHeightCapValue, requestBilinear, data);
-        } else if (colorModeValue == 3) {
-            let fgColor = gbFgColor.value;
-            let bgColor = gbBgColor.value;
-            let fgOpacity = gbFgOpacity.value;
-            imgData = wasm.processImageGbCustom(fgColor, fgOpacity, bgColor, ditherValue, brightnessValue, contrastValue, invertValue, edgeEnhancementLevelValue, imageHeightCapValue, requestBilinear, data);
-        } else if (colorModeValue <= 7) {
-            let scalingVal = parseInt(scaling.value);
-            let lcdModeVal = parseInt(document.querySelector('input[name="lcdMode"]:checked').value);
-            imgData = wasm.processImageGbc(scalingVal, lcdModeVal, colorModeValue - 4, imageHeightCapValue, requestBilinear, data);
-        } else {
-            let scalingVal = parseInt(scaling.value);
-            let parVal = document.querySelector('input[name="par"]:checked').value;
-            let explicitAspectRatio = false;
-            if (parVal == "auto") {
-                parVal = 0;
+        try {
+            if (colorModeValue < 3) {
+                imgData = wasm.processImageGb(colorModeValue, ditherValue, brightnessValue, contrastValue, invertValue, edgeEnhancementLevelValue, imageHeightCapValue, requestBilinear, ...wearVals, verticalLinesVal, targetWidthVal, targetHeightVal, outputFitVal, canvasColorVal, data);
+            } else if (colorModeValue == 3) {
+                let fgColor = gbFgColor.value;
+                let bgColor = gbBgColor.value;
+                let fgOpacity = gbFgOpacity.value;
+                imgData = wasm.processImageGbCustom(fgColor, fgOpacity, bgColor, gbShadeOpacity, ditherValue, brightnessValue, contrastValue, invertValue, edgeEnhancementLevelValue, imageHeightCapValue, requestBilinear, ...wearVals, verticalLinesVal, targetWidthVal, targetHeightVal, outputFitVal, canvasColorVal, data);
+            } else if (colorModeValue == 14) {
+                let scalingVal = parseInt(scaling.value);
+                let lcdModeVal = parseInt(document.querySelector('input[name="lcdMode"]:checked').value);
+                let gridStrengthVal = parseInt(gridStrength.value) / 100.0;
+                let subpixelOrderVal = parseInt(document.querySelector('input[name="subpixelOrder"]:checked').value);
+                let subpixelOrientationVal = parseInt(document.querySelector('input[name="subpixelOrientation"]:checked').value);
+                let subpixelFillVal = parseInt(subpixelFill.value) / 100.0;
+                let paletteVal = parseInt(document.querySelector('input[name="gbColorizePalette"]:checked').value);
+                let maskData = new Uint8Array(0);
+                if (gbColorizeMaskInput.files.length > 0) {
+                    maskData = new Uint8Array(await gbColorizeMaskInput.files[0].arrayBuffer());
+                }
+                imgData = wasm.processImageGbcColorize(paletteVal, gbColorizeGameTitle.value, gbColorizeNintendo.checked, scalingVal, lcdModeVal, gridStrengthVal, subpixelOrderVal, subpixelOrientationVal, subpixelFillVal, imageHeightCapValue, targetWidthVal, targetHeightVal, outputFitVal, canvasColorVal, maskData, data);
+            } else if (colorModeValue == 15) {
+                let scalingVal = parseInt(scaling.value);
+                let lcdModeVal = parseInt(document.querySelector('input[name="lcdMode"]:checked').value);
+                let gridStrengthVal = parseInt(gridStrength.value) / 100.0;
+                let subpixelOrderVal = parseInt(document.querySelector('input[name="subpixelOrder"]:checked').value);
+                let subpixelOrientationVal = parseInt(document.querySelector('input[name="subpixelOrientation"]:checked').value);
+                let subpixelFillVal = parseInt(subpixelFill.value) / 100.0;
+                let displayModeVal = parseInt(document.querySelector('input[name="gbOnGbaMode"]:checked').value);
+                let screenVal = parseInt(document.querySelector('input[name="gbOnGbaScreen"]:checked').value);
+                imgData = wasm.processImageGbOnGba(displayModeVal, scalingVal, lcdModeVal, gridStrengthVal, subpixelOrderVal, subpixelOrientationVal, subpixelFillVal, screenVal, targetWidthVal, targetHeightVal, outputFitVal, canvasColorVal, data);
+            } else if (colorModeValue != 8) {
+                let scalingVal = parseInt(scaling.value);
+                let lcdModeVal = parseInt(document.querySelector('input[name="lcdMode"]:checked').value);
+                let gridStrengthVal = parseInt(gridStrength.value) / 100.0;
+                let subpixelOrderVal = parseInt(document.querySelector('input[name="subpixelOrder"]:checked').value);
+                let subpixelOrientationVal = parseInt(document.querySelector('input[name="subpixelOrientation"]:checked').value);
+                let subpixelFillVal = parseInt(subpixelFill.value) / 100.0;
+                let gbcColorMode = selectedGbcColorMode();
+                let lutModeVal = parseInt(document.querySelector('input[name="lutMode"]:checked').value);
+                let lutInterpolationVal = parseInt(document.querySelector('input[name="lutInterpolation"]:checked').value);
+                let lutData = new Uint8Array(0);
+                if (lutInput.files.length > 0) {
+                    lutData = new Uint8Array(await lutInput.files[0].arrayBuffer());
+                }
+                let ambientBrightnessVal = parseInt(ambientBrightness.value) / 100.0;
+                let ambientTemperatureVal = parseFloat(ambientTemperature.value);
+                let glareVal = parseInt(glare.value) / 100.0;
+                let viewVerticalVal = parseFloat(viewVertical.value);
+                let viewHorizontalVal = parseFloat(viewHorizontal.value);
+                let tiltGradientVal = parseFloat(tiltGradient.value);
+                let quantizeModeVal = parseInt(document.querySelector('input[name="quantizeMode"]:checked').value);
+                let quantizeDitherVal = parseInt(document.querySelector('input[name="quantizeDither"]:checked').value);
+                let sourceCorrectionVal = parseInt(document.querySelector('input[name="sourceCorrection"]:checked').value);
+                let sourceCorrectionInfo = document.getElementById('sourceCorrectionInfo');
+                sourceCorrectionInfo.innerText = "";
+                if (sourceCorrectionVal >= 0) {
+                    // Recover the raw colours first, then render them like any other screenshot
+                    let inverse = wasm.invertColorCorrection(sourceCorrectionVal, imageHeightCapValue, requestBilinear, data);
+                    data = base64ToBytes(inverse.image);
+                    sourceCorrectionInfo.innerText = "Estimated clipped colours: " + (inverse.clipped * 100).toFixed(1) + "%, out of range: " + (inverse.outOfRange * 100).toFixed(1) + "%";
+                }
+                imgData = wasm.processImageGbc(scalingVal, lcdModeVal, gridStrengthVal, subpixelOrderVal, subpixelOrientationVal, subpixelFillVal, gbcColorMode, imageHeightCapValue, requestBilinear, lutModeVal, lutInterpolationVal, lutData, ambientLight.checked, ambientBrightnessVal, ambientTemperatureVal, glareVal, frontLight.checked, viewingAngle.checked, viewVerticalVal, viewHorizontalVal, tiltGradientVal, quantizeModeVal, quantizeDitherVal, ...adjustmentVals, ...wearVals, targetWidthVal, targetHeightVal, outputFitVal, canvasColorVal, data);
             } else {
-                let aspectRatio = parVal.split(":");
-                let aspectRatioX = parseFloat(aspectRatio[0]);
-                let aspectRatioY = parseFloat(aspectRatio[1]);
-                parVal = aspectRatioX / aspectRatioY;
-                explicitAspectRatio = true;
+                let scalingVal = parseInt(scaling.value);
+                let parVal = document.querySelector('input[name="par"]:checked').value;
+                let explicitAspectRatio = false;
+                if (parVal == "auto") {
+                    parVal = 0;
+                } else {
+                    let aspectRatio = parVal.split(":");
+                    let aspectRatioX = parseFloat(aspectRatio[0]);
+                    let aspectRatioY = parseFloat(aspectRatio[1]);
+                    parVal = aspectRatioX / aspectRatioY;
+                    explicitAspectRatio = true;
+                }
+                crtSettings.scanlineWeight = parseInt(scanlineWeight.value) / 100.0;
+                crtSettings.maskStrength = parseInt(maskStrength.value) / 100.0;
+                crtSettings.maskCompensation = parseInt(maskCompensation.value) / 100.0;
+                crtSettings.maskType = parseInt(document.querySelector('input[name="maskType"]:checked').value);
+                crtSettings.maskResolution = parseInt(document.querySelector('input[name="maskResolution"]:checked').value);
+                crtSettings.gamma = parseInt(crtGamma.value) / 10.0;
+                crtSettings.lum = parseInt(crtLum.value) / 100.0;
+                // Flat screen when curvature is switched off
+                let geometryVal = (id, scale, flat) => crtCurvature.checked ? parseInt(document.getElementById(id).value) / scale : flat;
+                crtSettings.curvatureX = geometryVal('curvatureX', 100.0, 0.0);
+                crtSettings.curvatureY = geometryVal('curvatureY', 100.0, 0.0);
+                crtSettings.cornerRadius = geometryVal('cornerRadius', 100.0, 0.0);
+                crtSettings.cornerSmoothness = geometryVal('cornerSmoothness', 100.0, 0.0);
+                crtSettings.overscan = geometryVal('overscan', 100.0, 1.0);
+                crtSettings.tiltX = geometryVal('tiltX', 1.0, 0.0);
+                crtSettings.tiltY = geometryVal('tiltY', 1.0, 0.0);
+                // Without a file the bundled LUT is used
+                let crtLutData = new Uint8Array(0);
+                if (crtLutInput.files.length > 0) {
+                    crtLutData = new Uint8Array(await crtLutInput.files[0].arrayBuffer());
+                }
+                imgData = wasm.processImageCrt(scalingVal, explicitAspectRatio, parVal, imageHeightCapValue, requestBilinear, crtSettings, crtIdentityLut.checked, crtLutData, ...adjustmentVals, targetWidthVal, targetHeightVal, outputFitVal, canvasColorVal, data);
             }
-            imgData = wasm.processImageCrt(scalingVal, explicitAspectRatio, parVal, imageHeightCapValue, requestBilinear, data);
+        } catch (e) {
+            errorText.innerText = e;
+            return;
         }
 
+        errorText.innerText = "";
+
         // Preview
         fileOutput.src = "data:image/png;base64," + imgData;
 